@@ -1,49 +1,420 @@
 #[macro_use]
 extern crate maplit;
 
-use std::cmp::Ordering;
-use std::collections::{BTreeSet, HashMap};
+use std::cell::Cell;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::rc::Rc;
 use std::slice::Iter;
 
 use linked_hash_map::LinkedHashMap;
-use log::{error, warn};
+use log::warn;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 #[serde(untagged)]
 enum KeyPressConf {
-	Mod { modifiers: Vec<String>, key: String },
+	TapHold {
+		tap: Box<KeyPressConf>,
+		hold: Box<KeyPressConf>,
+		// Milliseconds the key has to be held before it resolves to `hold`
+		timeout: Option<Timestamp>,
+		// Resolve to `hold` if another key is pressed and released while this one is held
+		#[serde(default)]
+		permissive_hold: bool,
+		// Resolve to `hold` as soon as another key is pressed while this one is held
+		#[serde(default)]
+		hold_on_other_key_press: bool,
+	},
+	TapDance {
+		// The action of each number of taps, starting from a single tap
+		tap_dance: Vec<KeyPressConf>,
+		// Milliseconds within which the next tap has to follow
+		timeout: Option<Timestamp>,
+	},
+	Layer {
+		layer: String,
+		#[serde(default)]
+		mode: LayerMode,
+	},
+	Macro {
+		#[serde(rename = "macro")]
+		steps: Vec<MacroStepConf>,
+		// Milliseconds between the steps of the macro
+		delay: Option<Timestamp>,
+	},
+	Unicode {
+		unicode: String,
+	},
+	OneShot {
+		one_shot: String,
+		// Milliseconds after which an armed one-shot modifier is cancelled
+		timeout: Option<Timestamp>,
+	},
+	// Types `tap_alone` when the key is released without any other key pressed meanwhile
+	TapAlone {
+		key: String,
+		tap_alone: Box<KeyPressConf>,
+		// Milliseconds the key can be held to still type `tap_alone`
+		timeout: Option<Timestamp>,
+	},
+	// Keys that are all held as long as the source key is held
+	Chord {
+		chord: Vec<String>,
+	},
+	Mod {
+		#[serde(default)]
+		modifiers: Vec<String>,
+		key: String,
+		// Held modifiers that are released while the key is held
+		#[serde(default)]
+		suppress: Vec<String>,
+	},
 	Key(String),
 }
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+enum MacroStepConf {
+	Text { text: String },
+	Press(KeyPressConf),
+}
+
+impl MacroStepConf {
+	// The keys of text steps are validated when they are typed
+	fn all_keys(&self) -> Vec<&String> {
+		match self {
+			MacroStepConf::Press(press) => press.all_keys(),
+			MacroStepConf::Text { .. } => vec![],
+		}
+	}
+
+	fn key_presses(
+		&self,
+		mut str_to_code: impl FnMut(&String) -> Option<i32>,
+	) -> Result<Vec<KeyPress>> {
+		match self {
+			MacroStepConf::Press(press) => Ok(vec![press.key_press(str_to_code)?]),
+			MacroStepConf::Text { text } => text
+				.chars()
+				.map(|c| type_char(c, &mut str_to_code))
+				.collect(),
+		}
+	}
+}
+
+// How unicode characters are typed, the hexadecimal code point is typed between the prefix and the suffix
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+struct KbctUnicodeConf {
+	prefix: Vec<MacroStepConf>,
+	suffix: Vec<MacroStepConf>,
+	// Milliseconds between the keys
+	delay: Option<Timestamp>,
+}
+
+impl KbctUnicodeConf {
+	// The input method of GTK and IBus
+	fn ibus() -> KbctUnicodeConf {
+		KbctUnicodeConf {
+			prefix: vec![MacroStepConf::Press(KeyPressConf::Mod {
+				modifiers: vec!["leftctrl".to_string(), "leftshift".to_string()],
+				key: "u".to_string(),
+				suppress: vec![],
+			})],
+			suffix: vec![MacroStepConf::Press(KeyPressConf::Key("space".to_string()))],
+			delay: None,
+		}
+	}
+
+	fn all_keys(&self) -> Vec<&String> {
+		self.prefix
+			.iter()
+			.chain(self.suffix.iter())
+			.flat_map(|x| x.all_keys())
+			.collect()
+	}
+}
+
+// What key actions can refer to besides key names
+struct KbctConfContext<'a> {
+	layer_index: HashMap<&'a String, usize>,
+	unicode_input: &'a KbctUnicodeConf,
+}
+
 impl KeyPressConf {
+	const TRANSPARENT: &'static str = "transparent";
+	const BLOCKED: &'static str = "blocked";
+	const LEADER: &'static str = "leader";
+	const CAPS_WORD: &'static str = "caps_word";
+	// Same as `blocked`, reads better outside of layers
+	const NONE: &'static str = "none";
+
+	fn is_reserved(key: &str) -> bool {
+		key == KeyPressConf::TRANSPARENT
+			|| key == KeyPressConf::BLOCKED
+			|| key == KeyPressConf::LEADER
+			|| key == KeyPressConf::CAPS_WORD
+			|| key == KeyPressConf::NONE
+	}
+
 	fn all_keys(&self) -> Vec<&String> {
 		match self {
+			KeyPressConf::Key(k) if KeyPressConf::is_reserved(k) => vec![],
 			KeyPressConf::Key(k) => vec![k],
-			KeyPressConf::Mod { modifiers, key } => {
-				modifiers.iter().chain(std::iter::once(key)).collect()
+			KeyPressConf::Mod {
+				modifiers,
+				key,
+				suppress,
+			} => modifiers
+				.iter()
+				.chain(std::iter::once(key))
+				.chain(suppress.iter())
+				.collect(),
+			KeyPressConf::Chord { chord } => chord.iter().collect(),
+			KeyPressConf::TapHold { tap, hold, .. } => {
+				tap.all_keys().into_iter().chain(hold.all_keys()).collect()
+			}
+			KeyPressConf::TapDance { tap_dance, .. } => {
+				tap_dance.iter().flat_map(|x| x.all_keys()).collect()
 			}
+			KeyPressConf::OneShot { one_shot, .. } => vec![one_shot],
+			KeyPressConf::TapAlone { key, tap_alone, .. } => {
+				std::iter::once(key).chain(tap_alone.all_keys()).collect()
+			}
+			KeyPressConf::Layer { .. } | KeyPressConf::Unicode { .. } => vec![],
+			KeyPressConf::Macro { steps, .. } => steps.iter().flat_map(|x| x.all_keys()).collect(),
+		}
+	}
+
+	fn key_action(
+		&self,
+		mut str_to_code: impl FnMut(&String) -> Option<i32>,
+		context: &KbctConfContext,
+	) -> Result<KeyAction> {
+		match self {
+			KeyPressConf::TapHold {
+				tap,
+				hold,
+				timeout,
+				permissive_hold,
+				hold_on_other_key_press,
+			} => {
+				// Passed as a trait object to not instantiate `key_action` recursively
+				let str_to_code: &mut dyn FnMut(&String) -> Option<i32> = &mut str_to_code;
+				let hold = hold.key_action(&mut *str_to_code, context)?;
+				if matches!(hold, KeyAction::TapHold(_) | KeyAction::TapDance(_)) {
+					return Err(KbctError::Error(format!(
+						"Tap-hold keys can't be held as {:?}",
+						hold
+					)));
+				}
+				Ok(KeyAction::TapHold(TapHold {
+					tap: tap.key_press(&mut *str_to_code)?,
+					hold: Box::new(hold),
+					timeout: timeout.unwrap_or(TapHold::DEFAULT_TIMEOUT),
+					permissive_hold: *permissive_hold,
+					hold_on_other_key_press: *hold_on_other_key_press,
+				}))
+			}
+			KeyPressConf::TapDance { tap_dance, timeout } => {
+				if tap_dance.is_empty() {
+					return Err(KbctError::Error(
+						"Tap dances need at least one action".to_string(),
+					));
+				}
+				// Passed as a trait object to not instantiate `key_action` recursively
+				let str_to_code: &mut dyn FnMut(&String) -> Option<i32> = &mut str_to_code;
+				Ok(KeyAction::TapDance(TapDance {
+					actions: tap_dance
+						.iter()
+						.map(|x| x.key_action(&mut *str_to_code, context))
+						.collect::<Result<_>>()?,
+					timeout: timeout.unwrap_or(TapDance::DEFAULT_TIMEOUT),
+				}))
+			}
+			KeyPressConf::TapAlone {
+				key,
+				tap_alone,
+				timeout,
+			} => Ok(KeyAction::TapAlone(TapAlone {
+				key: KeyPressConf::Key(key.clone()).key_press(&mut str_to_code)?,
+				tap: tap_alone.key_press(&mut str_to_code)?,
+				timeout: *timeout,
+			})),
+			KeyPressConf::OneShot { one_shot, timeout } => Ok(KeyAction::OneShot(OneShot {
+				modifier: str_to_code(one_shot).unwrap(),
+				timeout: *timeout,
+			})),
+			KeyPressConf::Key(k) if k == KeyPressConf::TRANSPARENT => Ok(KeyAction::Transparent),
+			KeyPressConf::Key(k) if k == KeyPressConf::BLOCKED || k == KeyPressConf::NONE => {
+				Ok(KeyAction::Blocked)
+			}
+			KeyPressConf::Key(k) if k == KeyPressConf::LEADER => Ok(KeyAction::Leader),
+			KeyPressConf::Key(k) if k == KeyPressConf::CAPS_WORD => Ok(KeyAction::CapsWord),
+			KeyPressConf::Macro { steps, delay } => {
+				let mut key_presses = vec![];
+				for step in steps {
+					key_presses.extend(step.key_presses(&mut str_to_code)?);
+				}
+				Ok(KeyAction::Macro(Macro {
+					steps: key_presses,
+					delay: delay.unwrap_or_default(),
+				}))
+			}
+			KeyPressConf::Unicode { unicode } => {
+				let input = context.unicode_input;
+				let mut key_presses = vec![];
+				for c in unicode.chars() {
+					for step in input.prefix.iter() {
+						key_presses.extend(step.key_presses(&mut str_to_code)?);
+					}
+					for digit in format!("{:x}", c as u32).chars() {
+						key_presses.push(type_char(digit, &mut str_to_code)?);
+					}
+					for step in input.suffix.iter() {
+						key_presses.extend(step.key_presses(&mut str_to_code)?);
+					}
+				}
+				Ok(KeyAction::Macro(Macro {
+					steps: key_presses,
+					delay: input.delay.unwrap_or_default(),
+				}))
+			}
+			KeyPressConf::Layer { layer, mode } => match context.layer_index.get(layer) {
+				Some(layer) => Ok(KeyAction::Layer(LayerAction {
+					layer: *layer,
+					mode: *mode,
+				})),
+				None => Err(KbctError::Error(format!("Unknown layer: {:?}", layer))),
+			},
+			_ => Ok(KeyAction::Press(self.key_press(str_to_code)?)),
 		}
 	}
 
-	fn key_press(&self, mut str_to_code: impl FnMut(&String) -> Option<i32>) -> KeyPress {
+	fn key_press(&self, mut str_to_code: impl FnMut(&String) -> Option<i32>) -> Result<KeyPress> {
 		match self {
-			KeyPressConf::Key(key) => KeyPress {
+			KeyPressConf::Key(key) if !KeyPressConf::is_reserved(key) => Ok(KeyPress {
 				code: str_to_code(key).unwrap(),
 				modifiers: Default::default(),
-			},
-			KeyPressConf::Mod { modifiers, key } => KeyPress {
+				chord: vec![],
+				suppressed: Default::default(),
+			}),
+			KeyPressConf::Mod {
+				modifiers,
+				key,
+				suppress,
+			} => Ok(KeyPress {
 				code: str_to_code(key).unwrap(),
 				modifiers: modifiers.iter().map(|k| str_to_code(k).unwrap()).collect(),
+				chord: vec![],
+				suppressed: suppress.iter().map(|k| str_to_code(k).unwrap()).collect(),
+			}),
+			// The last key of the chord is the one that repeats
+			KeyPressConf::Chord { chord } => match chord.split_last() {
+				Some((key, chord)) => Ok(KeyPress {
+					code: str_to_code(key).unwrap(),
+					modifiers: Default::default(),
+					chord: chord.iter().map(|k| str_to_code(k).unwrap()).collect(),
+					suppressed: Default::default(),
+				}),
+				None => Err(KbctError::Error("Chords need at least one key".to_string())),
 			},
+			_ => Err(KbctError::Error(format!(
+				"Expected a key with optional modifiers, got {:?}",
+				self
+			))),
 		}
 	}
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+// The key that types a character on a US layout and whether it has to be shifted
+fn char_to_key_name(c: char) -> Option<(String, bool)> {
+	let (name, shift) = match c {
+		'a'..='z' | '0'..='9' => return Some((c.to_string(), false)),
+		'A'..='Z' => return Some((c.to_ascii_lowercase().to_string(), true)),
+		' ' => ("space", false),
+		'\n' => ("enter", false),
+		'\t' => ("tab", false),
+		'-' => ("minus", false),
+		'_' => ("minus", true),
+		'=' => ("equal", false),
+		'+' => ("equal", true),
+		'[' => ("leftbrace", false),
+		'{' => ("leftbrace", true),
+		']' => ("rightbrace", false),
+		'}' => ("rightbrace", true),
+		';' => ("semicolon", false),
+		':' => ("semicolon", true),
+		'\'' => ("apostrophe", false),
+		'"' => ("apostrophe", true),
+		'`' => ("grave", false),
+		'~' => ("grave", true),
+		'\\' => ("backslash", false),
+		'|' => ("backslash", true),
+		',' => ("comma", false),
+		'<' => ("comma", true),
+		'.' => ("dot", false),
+		'>' => ("dot", true),
+		'/' => ("slash", false),
+		'?' => ("slash", true),
+		'!' => ("1", true),
+		'@' => ("2", true),
+		'#' => ("3", true),
+		'$' => ("4", true),
+		'%' => ("5", true),
+		'^' => ("6", true),
+		'&' => ("7", true),
+		'*' => ("8", true),
+		'(' => ("9", true),
+		')' => ("0", true),
+		_ => return None,
+	};
+	Some((name.to_string(), shift))
+}
+
+fn type_char(c: char, mut str_to_code: impl FnMut(&String) -> Option<i32>) -> Result<KeyPress> {
+	let not_typeable = || KbctError::Error(format!("Can not type {:?}", c));
+	let (name, shift) = char_to_key_name(c).ok_or_else(not_typeable)?;
+	let mut modifiers = KeySet::new();
+	if shift {
+		modifiers.insert(str_to_code(&"leftshift".to_string()).ok_or_else(not_typeable)?);
+	}
+	Ok(KeyPress {
+		code: str_to_code(&name).ok_or_else(not_typeable)?,
+		modifiers,
+		chord: vec![],
+		suppressed: Default::default(),
+	})
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
 struct KbctComplexConf {
+	// Layers can be referenced by name in layer actions
+	name: Option<String>,
+	// Layers without modifiers are only activated by layer actions
+	#[serde(default)]
 	modifiers: Vec<String>,
+	// The layer is only active if no other modifiers are held
+	#[serde(default)]
+	exact: bool,
+	// Keys that prevent the layer from being active while they are held
+	#[serde(default)]
+	without: Vec<String>,
+	// Whether the layer keys are matched against the pressed keys or the keys they are mapped to
+	#[serde(default, rename = "match")]
+	match_on: LayerMatch,
+	// Active layers with higher priority take precedence
+	#[serde(default)]
+	priority: i32,
 	keymap: HashMap<String, KeyPressConf>,
+	// Tapping the modifiers applies the layer to the next key press
+	#[serde(default)]
+	one_shot: bool,
+	// Milliseconds after which an armed one-shot layer is cancelled
+	one_shot_timeout: Option<Timestamp>,
+	// The LED of the keyboard that is lit while the layer is active
+	led: Option<Led>,
 }
 
 pub type KbctRootConf = Vec<KbctConf>;
@@ -52,13 +423,273 @@ pub type Result<T> = std::result::Result<T, KbctError>;
 #[derive(Debug, Clone)]
 pub struct KeyPress {
 	code: Keycode,
+	// Released on the next key event
+	modifiers: KeySet,
+	// Pressed before the code and held along with it
+	chord: Vec<Keycode>,
+	// Held modifiers that are released while the key is held
+	suppressed: KeySet,
+}
+
+#[derive(Debug, Clone)]
+pub struct TapHold {
+	tap: KeyPress,
+	// Any action but tap-holds and tap dances, such as a modifier or a layer
+	hold: Box<KeyAction>,
+	timeout: Timestamp,
+	permissive_hold: bool,
+	hold_on_other_key_press: bool,
+}
+
+impl TapHold {
+	const DEFAULT_TIMEOUT: Timestamp = 200;
+}
+
+#[derive(Debug, Clone)]
+pub struct TapDance {
+	// Tap-hold actions resolve to their hold action if the last tap is held past the timeout
+	actions: Vec<KeyAction>,
+	timeout: Timestamp,
+}
+
+impl TapDance {
+	const DEFAULT_TIMEOUT: Timestamp = 200;
+}
+
+#[derive(Debug, Clone)]
+pub struct TapAlone {
+	key: KeyPress,
+	tap: KeyPress,
+	timeout: Option<Timestamp>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OneShot {
+	modifier: Keycode,
+	timeout: Option<Timestamp>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Macro {
+	steps: Vec<KeyPress>,
+	// Milliseconds between the steps
+	delay: Timestamp,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LayerMode {
+	// Active while the key is held
+	#[default]
+	Momentary,
+	// Each press activates or deactivates the layer
+	Toggle,
+	// Activates the layer and deactivates all others
+	To,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LayerMatch {
+	// The physical keys activate the layer
+	#[default]
+	Source,
+	// Any keys that are mapped to the layer keys activate the layer
+	Mapped,
+}
+
+#[derive(Debug, Clone)]
+pub struct LayerAction {
+	layer: usize,
+	mode: LayerMode,
+}
+
+#[derive(Debug, Clone)]
+pub enum KeyAction {
+	Press(KeyPress),
+	TapHold(TapHold),
+	TapDance(TapDance),
+	TapAlone(TapAlone),
+	OneShot(OneShot),
+	Layer(LayerAction),
+	Macro(Macro),
+	// Falls through to the next active layer
+	Transparent,
+	// Drops the key events
+	Blocked,
+	// Starts a leader sequence
+	Leader,
+	// Turns caps word on or off
+	CapsWord,
+}
+
+impl KeyPress {
+	fn output_codes(&self) -> impl Iterator<Item = Keycode> + '_ {
+		std::iter::once(self.code)
+			.chain(self.modifiers.iter().copied())
+			.chain(self.chord.iter().copied())
+	}
+}
+
+impl KeyAction {
+	// The codes the action can write, the ones of layer actions are part of their layers
+	fn output_codes(&self) -> Vec<Keycode> {
+		match self {
+			KeyAction::Press(key_press) => key_press.output_codes().collect(),
+			KeyAction::TapHold(tap_hold) => tap_hold
+				.tap
+				.output_codes()
+				.chain(tap_hold.hold.output_codes())
+				.collect(),
+			KeyAction::TapDance(tap_dance) => tap_dance
+				.actions
+				.iter()
+				.flat_map(|x| x.output_codes())
+				.collect(),
+			KeyAction::TapAlone(tap_alone) => tap_alone
+				.key
+				.output_codes()
+				.chain(tap_alone.tap.output_codes())
+				.collect(),
+			KeyAction::OneShot(one_shot) => vec![one_shot.modifier],
+			KeyAction::Macro(key_macro) => key_macro
+				.steps
+				.iter()
+				.flat_map(|x| x.output_codes())
+				.collect(),
+			KeyAction::Layer(_)
+			| KeyAction::Transparent
+			| KeyAction::Blocked
+			| KeyAction::Leader
+			| KeyAction::CapsWord => vec![],
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+struct KbctCombo {
+	keys: KeySet,
+	target: KeyPress,
+	timeout: Timestamp,
+}
+
+impl KbctCombo {
+	const DEFAULT_TIMEOUT: Timestamp = 50;
+}
+
+#[derive(Debug)]
+struct KbctLayer {
+	priority: i32,
 	modifiers: KeySet,
+	exact: bool,
+	without: KeySet,
+	match_on: LayerMatch,
+	keymap: KeyMap,
+	one_shot: bool,
+	one_shot_timeout: Option<Timestamp>,
+	led: Option<Led>,
+}
+
+impl KbctLayer {
+	// The code of a held key that is matched against the layer keys
+	fn match_code(&self, source: Keycode, mapped: Keycode) -> Keycode {
+		match self.match_on {
+			LayerMatch::Source => source,
+			LayerMatch::Mapped => mapped,
+		}
+	}
+}
+
+// The leader sequences by their keys, a sequence can be a prefix of a longer one
+#[derive(Debug, Default)]
+struct KbctLeaderTrie {
+	action: Option<KeyAction>,
+	children: HashMap<Keycode, KbctLeaderTrie>,
+}
+
+impl KbctLeaderTrie {
+	const DEFAULT_TIMEOUT: Timestamp = 1000;
+
+	fn insert(&mut self, keys: &[Keycode], action: KeyAction) -> bool {
+		let node = keys
+			.iter()
+			.fold(self, |node, key| node.children.entry(*key).or_default());
+		node.action.replace(action).is_none()
+	}
+
+	fn get(&self, keys: impl Iterator<Item = Keycode>) -> Option<&KbctLeaderTrie> {
+		let mut node = self;
+		for key in keys {
+			node = node.children.get(&key)?;
+		}
+		Some(node)
+	}
+
+	fn actions(&self) -> Vec<&KeyAction> {
+		self.action
+			.iter()
+			.chain(self.children.values().flat_map(|x| x.actions()))
+			.collect()
+	}
+}
+
+#[derive(Debug, Default)]
+struct KbctCapsWord {
+	letters: KeySet,
+	shift: Option<Keycode>,
+	keys: HashMap<Keycode, KeyPress>,
+	timeout: Timestamp,
+}
+
+impl KbctCapsWord {
+	const DEFAULT_TIMEOUT: Timestamp = 5000;
+}
+
+#[derive(Debug, Default)]
+struct KbctAutoShift {
+	keys: KeySet,
+	shift: Option<Keycode>,
+	timeout: Timestamp,
+}
+
+impl KbctAutoShift {
+	const DEFAULT_TIMEOUT: Timestamp = 175;
+}
+
+#[derive(Debug, Default)]
+struct KbctSuppressWhileTyping {
+	keys: KeySet,
+	timeout: Timestamp,
+}
+
+impl KbctSuppressWhileTyping {
+	const DEFAULT_TIMEOUT: Timestamp = 300;
+	// The Linux codes of mouse, touch and other buttons start here, pressing them is not typing
+	const FIRST_BUTTON_CODE: Keycode = 0x100;
 }
 
+// Keys that do not consume armed one-shot modifiers when pressed
+const MODIFIER_KEYS: [&str; 8] = [
+	"leftctrl",
+	"rightctrl",
+	"leftshift",
+	"rightshift",
+	"leftalt",
+	"rightalt",
+	"leftmeta",
+	"rightmeta",
+];
+
 type Keycode = i32;
-type KeyMap = HashMap<Keycode, KeyPress>;
+// Time of the latest press of a key that is not a modifier or button, shared by the keyboards of a configuration
+pub type KbctTypingTime = Rc<Cell<Option<Timestamp>>>;
+// Milliseconds on a monotonic clock
+pub type Timestamp = u64;
+type KeyMap = HashMap<Keycode, KeyAction>;
 type KeySet = BTreeSet<Keycode>;
 type ComplexKeyMap = HashMap<KeySet, KeyMap>;
+type Layers = Vec<KbctLayer>;
+
 type KeyStateMap = LinkedHashMap<Keycode, KbctKeyState>;
 type LinkedHashSet<T> = LinkedHashMap<T, bool>;
 type KeySequenceSet = LinkedHashSet<Keycode>;
@@ -84,20 +715,179 @@ pub enum KbctError {
 	#[error("Regex Error")]
 	RegexError(#[from] regex::Error),
 
+	#[error("Nix Error {0}")]
+	NixError(#[from] nix::Error),
+
 	#[error("Kbct Error")]
 	Error(String),
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+struct KbctComboConf {
+	keys: Vec<String>,
+	target: KeyPressConf,
+	// Milliseconds within which all the keys have to be pressed
+	timeout: Option<Timestamp>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+struct KbctLeaderSequenceConf {
+	keys: Vec<String>,
+	target: KeyPressConf,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+struct KbctLeaderConf {
+	// Milliseconds within which the next key of a sequence has to be pressed
+	timeout: Option<Timestamp>,
+	sequences: Vec<KbctLeaderSequenceConf>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
+struct KbctCapsWordConf {
+	// Milliseconds after the last key of the word after which caps word ends
+	timeout: Option<Timestamp>,
+	// Keys other than letters that continue the word and what they type while caps word is active
+	keys: Option<HashMap<String, KeyPressConf>>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+struct KbctAutoShiftConf {
+	keys: Vec<String>,
+	// Milliseconds the key has to be held to be typed shifted
+	timeout: Option<Timestamp>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+struct KbctSuppressConf {
+	keys: Vec<String>,
+	// Milliseconds after the last typed key during which the keys are dropped
+	timeout: Option<Timestamp>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
+pub struct KbctMouseKeysConf {
+	// Pixels per second the pointer moves by once a key is pressed
+	speed: Option<u32>,
+	// Pixels per second the pointer accelerates to while the keys are held
+	max_speed: Option<u32>,
+	// Milliseconds it takes to accelerate to the maximum speed
+	acceleration: Option<Timestamp>,
+	// Wheel ticks per second while a wheel key is held
+	wheel_speed: Option<u32>,
+}
+
+impl KbctMouseKeysConf {
+	const DEFAULT_SPEED: u32 = 200;
+	const DEFAULT_MAX_SPEED: u32 = 1200;
+	const DEFAULT_ACCELERATION: Timestamp = 1000;
+	const DEFAULT_WHEEL_SPEED: u32 = 10;
+
+	// The speed of the pointer once the keys are held for the given milliseconds
+	pub fn pointer_speed(&self, held: Timestamp) -> f64 {
+		let speed = self.speed.unwrap_or(Self::DEFAULT_SPEED) as f64;
+		let max_speed = self.max_speed.unwrap_or(Self::DEFAULT_MAX_SPEED) as f64;
+		let acceleration = self.acceleration.unwrap_or(Self::DEFAULT_ACCELERATION);
+		if held >= acceleration {
+			return max_speed.max(speed);
+		}
+		speed + (max_speed - speed).max(0.0) * held as f64 / acceleration as f64
+	}
+
+	pub fn wheel_speed(&self) -> f64 {
+		self.wheel_speed.unwrap_or(Self::DEFAULT_WHEEL_SPEED) as f64
+	}
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+struct KbctAxisConf {
+	// Moves another axis instead
+	axis: Option<RelativeAxis>,
+	// Multiplies the motion, fractions are carried over to the next event
+	scale: Option<f64>,
+	#[serde(default)]
+	invert: bool,
+}
+
+// The bus type, vendor, product and version of an input device
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct KbctDeviceId {
+	pub bustype: u16,
+	pub vendor: u16,
+	pub product: u16,
+	pub version: u16,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
+pub struct KbctOutputConf {
+	// Name of the virtual output device, `Kbct-<keyboard name>` by default
+	name: Option<String>,
+	// Copies the id of the grabbed device to the output device
+	#[serde(default)]
+	copy_id: bool,
+	bustype: Option<u16>,
+	vendor: Option<u16>,
+	product: Option<u16>,
+	version: Option<u16>,
+}
+
+impl KbctOutputConf {
+	pub fn name(&self, keyboard: &str) -> String {
+		self.name
+			.clone()
+			.unwrap_or_else(|| format!("{}-{}", "Kbct", keyboard))
+	}
+
+	pub fn copy_id(&self) -> bool {
+		self.copy_id
+	}
+
+	// The id of the output device, the configured fields override the given id
+	pub fn id(&self, id: KbctDeviceId) -> KbctDeviceId {
+		KbctDeviceId {
+			bustype: self.bustype.unwrap_or(id.bustype),
+			vendor: self.vendor.unwrap_or(id.vendor),
+			product: self.product.unwrap_or(id.product),
+			version: self.version.unwrap_or(id.version),
+		}
+	}
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone)]
 pub struct KbctConf {
 	keyboards: Vec<String>,
 	keymap: Option<HashMap<String, KeyPressConf>>,
 	layers: Option<Vec<KbctComplexConf>>,
+	combos: Option<Vec<KbctComboConf>>,
+	unicode_input: Option<KbctUnicodeConf>,
+	leader: Option<KbctLeaderConf>,
+	caps_word: Option<KbctCapsWordConf>,
+	auto_shift: Option<KbctAutoShiftConf>,
+	// Milliseconds after the release of a key within which a press of the same key is dropped
+	debounce: Option<Timestamp>,
+	// Keys whose events are always dropped
+	disabled: Option<Vec<String>>,
+	suppress_while_typing: Option<KbctSuppressConf>,
+	mouse_keys: Option<KbctMouseKeysConf>,
+	axes: Option<HashMap<RelativeAxis, KbctAxisConf>>,
+	output: Option<KbctOutputConf>,
 }
 
 impl KbctConf {
 	pub fn keyboards(&self) -> Iter<'_, String> {
-		return self.keyboards.iter();
+		self.keyboards.iter()
+	}
+
+	pub fn debounce(&self) -> Option<Timestamp> {
+		self.debounce
+	}
+
+	pub fn mouse_keys(&self) -> KbctMouseKeysConf {
+		self.mouse_keys.clone().unwrap_or_default()
+	}
+
+	pub fn output(&self) -> KbctOutputConf {
+		self.output.clone().unwrap_or_default()
 	}
 }
 
@@ -107,130 +897,782 @@ impl KbctConf {
 	}
 }
 
-
 #[derive(Debug)]
 struct KbctKeyState {
 	time: u64,
 	mapped_code: Keycode,
+	// Codes held along with the mapped code
+	chord: Vec<Keycode>,
 	status: KbctKeyStatus,
 }
 
+// A tap-hold key that is held down but not yet resolved to its tap or hold action
+#[derive(Debug)]
+struct KbctPendingKey {
+	source: Keycode,
+	tap_hold: TapHold,
+	is_complex: bool,
+	time: Timestamp,
+}
+
+// A tap dance key that is being tapped, until the number of taps is known
+#[derive(Debug)]
+struct KbctTapDance {
+	source: Keycode,
+	tap_dance: TapDance,
+	is_complex: bool,
+	taps: usize,
+	held: bool,
+	// Time of the latest press or release of the key
+	time: Timestamp,
+}
+
+impl KbctTapDance {
+	// No more taps can follow the last action, only tap-hold actions depend on the key being held
+	fn is_complete(&self) -> bool {
+		let actions = &self.tap_dance.actions;
+		self.taps >= actions.len()
+			&& (!self.held || !matches!(actions.last(), Some(KeyAction::TapHold(_))))
+	}
+}
+
+// A leader key was pressed, the following keys are swallowed until they match a sequence
+#[derive(Debug)]
+struct KbctLeader {
+	// Events of the keys pressed after the leader key, replayed if they do not match
+	events: Vec<KbctEvent>,
+	held_keys: KeySet,
+	deadline: Timestamp,
+}
+
+impl KbctLeader {
+	fn keys(&self) -> impl Iterator<Item = Keycode> + '_ {
+		self.events
+			.iter()
+			.filter(|x| x.ev_type == KbctKeyStatus::Clicked)
+			.map(|x| x.code)
+	}
+}
+
+// An auto-shift key that is held down, until it is known whether it is typed shifted
+#[derive(Debug)]
+struct KbctAutoShiftKey {
+	source: Keycode,
+	key_press: KeyPress,
+	is_complex: bool,
+	time: Timestamp,
+}
+
+// A fired combo, its keys are dropped until all of them are released
+#[derive(Debug)]
+struct KbctHeldCombo {
+	// The key that stands for the combo in the later stages
+	source: Keycode,
+	held_keys: KeySet,
+	released: bool,
+}
+
+// One-shot modifiers and layer that were tapped and apply to the next key press
+// Each of them is cancelled at its own deadline, if it has a timeout
+#[derive(Debug, Default)]
+struct KbctOneShot {
+	modifiers: HashMap<Keycode, Option<Timestamp>>,
+	layer: Option<(usize, Option<Timestamp>)>,
+}
+
+impl KbctOneShot {
+	fn deadline(&self) -> Option<Timestamp> {
+		self.modifiers
+			.values()
+			.chain(self.layer.as_ref().map(|(_, deadline)| deadline))
+			.flatten()
+			.min()
+			.copied()
+	}
+
+	// Cancels the modifiers and the layer whose deadline has passed
+	fn expire(&mut self, now: Timestamp) {
+		let expired = |deadline: &Option<Timestamp>| deadline.is_some_and(|x| x <= now);
+		self.modifiers.retain(|_, deadline| !expired(deadline));
+		if self
+			.layer
+			.as_ref()
+			.is_some_and(|(_, deadline)| expired(deadline))
+		{
+			self.layer = None;
+		}
+	}
+}
+
 #[derive(Debug)]
 pub struct Kbct {
 	simple_map: KeyMap,
-	complex_map: ComplexKeyMap,
+	layers: Layers,
+	combos: Vec<KbctCombo>,
+	modifier_codes: KeySet,
 	source_to_mapped: KeyStateMap,
 	mapped_to_source: ReverseKeyMap,
 	transient_modifiers: KeySet,
+	// Clicked events of keys that may be part of a combo, until the combo is complete or broken
+	combo_events: Vec<KbctEvent>,
+	held_combos: Vec<KbctHeldCombo>,
+	// Actions resolved by combos and tap dances, by the key that stands for them, until it is released
+	resolved_actions: HashMap<Keycode, (KeyAction, bool)>,
+	pending_key: Option<KbctPendingKey>,
+	tap_dance: Option<KbctTapDance>,
+	leader_sequences: KbctLeaderTrie,
+	leader_timeout: Timestamp,
+	leader: Option<KbctLeader>,
+	caps_word: KbctCapsWord,
+	// Set while caps word is active, the word ends at the deadline
+	caps_word_deadline: Option<Timestamp>,
+	auto_shift: KbctAutoShift,
+	auto_shift_key: Option<KbctAutoShiftKey>,
+	disabled_keys: KeySet,
+	suppress_while_typing: KbctSuppressWhileTyping,
+	// Keys pressed while typing, their events are dropped until they are released
+	suppressed_keys: KeySet,
+	last_typed: KbctTypingTime,
+	// Events received while `pending_key` is unresolved, replayed once it resolves
+	buffered_events: VecDeque<KbctEvent>,
+	// One-shot keys that are held down, by source code
+	held_one_shots: HashMap<Keycode, OneShot>,
+	one_shot: KbctOneShot,
+	// Held keys with a layer action and the logic clock of their press
+	layer_keys: HashMap<Keycode, (LayerAction, u64)>,
+	// Layers locked by layer actions and the logic clock of their activation
+	locked_layers: HashMap<usize, u64>,
+	// Keys whose events are dropped until they are released
+	swallowed_keys: KeySet,
+	// Held keys with a tap alone action, along with the time they were pressed
+	tap_alone_keys: HashMap<Keycode, (TapAlone, Timestamp)>,
+	// Modifiers that were force released by held keys, by the source of the key
+	lifted_modifiers: HashMap<Keycode, Vec<Keycode>>,
+	axes: HashMap<RelativeAxis, KbctAxis>,
+	// Fractions of the scaled motions that are carried over to the next event, by axis
+	axis_remainders: HashMap<RelativeAxis, f64>,
+	last_clicked: Option<Keycode>,
 	logic_clock: u64,
+	// Time of the latest event or timeout, output events are stamped with it
+	now: Timestamp,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct KbctEvent {
 	pub code: Keycode,
 	pub ev_type: KbctKeyStatus,
+	pub time: Timestamp,
+	pub kind: KbctEventKind,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
-pub enum KbctKeyStatus {
-	ForceReleased,
-	Released,
-	Clicked,
-	Pressed,
+impl KbctEvent {
+	pub fn new(code: Keycode, ev_type: KbctKeyStatus, time: Timestamp) -> KbctEvent {
+		let kind = match MouseMotion::from_code(code) {
+			Some(_) => KbctEventKind::Mouse,
+			None => KbctEventKind::Key,
+		};
+		KbctEvent {
+			code,
+			ev_type,
+			time,
+			kind,
+		}
+	}
 }
 
-impl Kbct {
-	pub fn new_test(simple_keymap: KeyMap, complex_keymap: ComplexKeyMap) -> Kbct {
-		Kbct {
-			simple_map: simple_keymap,
-			complex_map: complex_keymap,
-			source_to_mapped: Default::default(),
-			mapped_to_source: Default::default(),
-			transient_modifiers: Default::default(),
-			logic_clock: 0,
+impl KbctEvent {
+	// Relative events are not held, so their status is always `Pressed`
+	pub fn relative(axis: RelativeAxis, value: i32, time: Timestamp) -> KbctEvent {
+		KbctEvent {
+			code: axis.code(),
+			ev_type: KbctKeyStatus::Pressed,
+			time,
+			kind: KbctEventKind::Relative(value),
 		}
 	}
+}
 
-	pub fn new(conf: KbctConf, key_code: impl Fn(&String) -> Option<i32>) -> Result<Kbct> {
-		let simple = conf.keymap.unwrap_or_default();
-		let complex = conf.layers.unwrap_or_default();
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum KbctEventKind {
+	// Written as a key event
+	Key,
+	// Starts or stops a mouse motion, the code is the code of a `MouseMotion`
+	Mouse,
+	// Moves a relative axis by the value, the code is the code of a `RelativeAxis`
+	Relative(i32),
+}
 
-		let str_to_code = |k| key_code(k).unwrap();
-		let str_to_code_pair =
-			|(k, v): (_, &KeyPressConf)| (str_to_code(k), v.key_press(&key_code));
+// The relative axes of pointing devices that are mapped, their codes follow the mouse motions
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RelativeAxis {
+	X,
+	Y,
+	Wheel,
+	HWheel,
+	// The high resolution wheels move by fractions of a tick, they follow the mapping of their wheel
+	#[serde(skip)]
+	WheelHiRes,
+	#[serde(skip)]
+	HWheelHiRes,
+}
 
-		let all_keys = simple
-			.iter()
-			.flat_map(|(k, v)| std::iter::once(k).chain(v.all_keys()))
-			.chain(complex.iter().flat_map(|x| {
-				x.modifiers.iter().chain(
-					x.keymap
-						.iter()
-						.flat_map(|(k, v)| std::iter::once(k).chain(v.all_keys())),
-				)
-			}));
+impl RelativeAxis {
+	const ALL: [RelativeAxis; 6] = [
+		RelativeAxis::X,
+		RelativeAxis::Y,
+		RelativeAxis::Wheel,
+		RelativeAxis::HWheel,
+		RelativeAxis::WheelHiRes,
+		RelativeAxis::HWheelHiRes,
+	];
+	// The motion of a high resolution wheel per tick of its wheel
+	const HI_RES_TICK: i32 = 120;
+	const FIRST_CODE: Keycode = 0x310;
 
-		let unknown_keys: BTreeSet<&String> = all_keys.filter(|x| key_code(*x).is_none()).collect();
-		if !unknown_keys.is_empty() {
-			return Err(KbctError::Error(format!(
-				"Configuration contains unknown keys: {:?}",
-				unknown_keys
-			)));
+	pub fn name(self) -> &'static str {
+		match self {
+			RelativeAxis::X => "x",
+			RelativeAxis::Y => "y",
+			RelativeAxis::Wheel => "wheel",
+			RelativeAxis::HWheel => "hwheel",
+			RelativeAxis::WheelHiRes => "wheel_hi_res",
+			RelativeAxis::HWheelHiRes => "hwheel_hi_res",
 		}
+	}
 
-		let simple_map: KeyMap = simple.iter().map(str_to_code_pair).collect();
+	pub fn code(self) -> Keycode {
+		RelativeAxis::FIRST_CODE + self as Keycode
+	}
 
-		let complex_map: HashMap<KeySet, KeyMap> = complex
-			.iter()
+	pub fn from_code(code: Keycode) -> Option<RelativeAxis> {
+		if code < RelativeAxis::FIRST_CODE {
+			return None;
+		}
+		RelativeAxis::ALL
+			.get((code - RelativeAxis::FIRST_CODE) as usize)
+			.copied()
+	}
+
+	fn hi_res(self) -> Option<RelativeAxis> {
+		match self {
+			RelativeAxis::Wheel => Some(RelativeAxis::WheelHiRes),
+			RelativeAxis::HWheel => Some(RelativeAxis::HWheelHiRes),
+			_ => None,
+		}
+	}
+
+	fn low_res(self) -> RelativeAxis {
+		match self {
+			RelativeAxis::WheelHiRes => RelativeAxis::Wheel,
+			RelativeAxis::HWheelHiRes => RelativeAxis::HWheel,
+			x => x,
+		}
+	}
+
+	// The wheel key a tick of the wheel is typed as
+	fn wheel_motion(self, value: i32) -> Option<MouseMotion> {
+		match (self, value > 0) {
+			(RelativeAxis::Wheel, true) => Some(MouseMotion::WheelUp),
+			(RelativeAxis::Wheel, false) => Some(MouseMotion::WheelDown),
+			(RelativeAxis::HWheel, true) => Some(MouseMotion::WheelRight),
+			(RelativeAxis::HWheel, false) => Some(MouseMotion::WheelLeft),
+			_ => None,
+		}
+	}
+}
+
+// The LEDs of a keyboard that can show the active layers
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Led {
+	NumLock,
+	CapsLock,
+	ScrollLock,
+	Compose,
+	Kana,
+}
+
+// The axis the motion of a relative axis is moved to and the factor it is multiplied by
+#[derive(Debug)]
+struct KbctAxis {
+	axis: RelativeAxis,
+	scale: f64,
+}
+
+// Pointer motions and wheel scrolls that keys can be mapped to
+// They are held like keys, with codes that follow the range of the linux key codes
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum MouseMotion {
+	Up,
+	Down,
+	Left,
+	Right,
+	WheelUp,
+	WheelDown,
+	WheelLeft,
+	WheelRight,
+}
+
+impl MouseMotion {
+	const ALL: [MouseMotion; 8] = [
+		MouseMotion::Up,
+		MouseMotion::Down,
+		MouseMotion::Left,
+		MouseMotion::Right,
+		MouseMotion::WheelUp,
+		MouseMotion::WheelDown,
+		MouseMotion::WheelLeft,
+		MouseMotion::WheelRight,
+	];
+	const FIRST_CODE: Keycode = 0x300;
+
+	pub fn name(self) -> &'static str {
+		match self {
+			MouseMotion::Up => "mouse_up",
+			MouseMotion::Down => "mouse_down",
+			MouseMotion::Left => "mouse_left",
+			MouseMotion::Right => "mouse_right",
+			MouseMotion::WheelUp => "mouse_wheel_up",
+			MouseMotion::WheelDown => "mouse_wheel_down",
+			MouseMotion::WheelLeft => "mouse_wheel_left",
+			MouseMotion::WheelRight => "mouse_wheel_right",
+		}
+	}
+
+	pub fn code(self) -> Keycode {
+		MouseMotion::FIRST_CODE + self as Keycode
+	}
+
+	pub fn from_code(code: Keycode) -> Option<MouseMotion> {
+		if code < MouseMotion::FIRST_CODE {
+			return None;
+		}
+		MouseMotion::ALL
+			.get((code - MouseMotion::FIRST_CODE) as usize)
+			.copied()
+	}
+
+	pub fn from_name(name: &str) -> Option<MouseMotion> {
+		MouseMotion::ALL.iter().find(|x| x.name() == name).copied()
+	}
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum KbctKeyStatus {
+	ForceReleased,
+	Released,
+	Clicked,
+	Pressed,
+}
+
+impl Kbct {
+	pub fn new_test(simple_keymap: KeyMap, complex_keymap: ComplexKeyMap) -> Kbct {
+		Kbct {
+			simple_map: simple_keymap,
+			layers: complex_keymap
+				.into_iter()
+				.map(|(modifiers, keymap)| KbctLayer {
+					priority: 0,
+					modifiers,
+					exact: false,
+					without: Default::default(),
+					match_on: LayerMatch::Source,
+					keymap,
+					one_shot: false,
+					one_shot_timeout: None,
+					led: None,
+				})
+				.collect(),
+			combos: vec![],
+			modifier_codes: Default::default(),
+			source_to_mapped: Default::default(),
+			mapped_to_source: Default::default(),
+			transient_modifiers: Default::default(),
+			combo_events: vec![],
+			held_combos: vec![],
+			resolved_actions: Default::default(),
+			pending_key: None,
+			tap_dance: None,
+			leader_sequences: Default::default(),
+			leader_timeout: KbctLeaderTrie::DEFAULT_TIMEOUT,
+			leader: None,
+			caps_word: Default::default(),
+			caps_word_deadline: None,
+			auto_shift: Default::default(),
+			auto_shift_key: None,
+			disabled_keys: Default::default(),
+			suppress_while_typing: Default::default(),
+			suppressed_keys: Default::default(),
+			last_typed: Default::default(),
+			buffered_events: Default::default(),
+			held_one_shots: Default::default(),
+			one_shot: Default::default(),
+			layer_keys: Default::default(),
+			locked_layers: Default::default(),
+			swallowed_keys: Default::default(),
+			tap_alone_keys: Default::default(),
+			lifted_modifiers: Default::default(),
+			axes: Default::default(),
+			axis_remainders: Default::default(),
+			last_clicked: None,
+			logic_clock: 0,
+			now: 0,
+		}
+	}
+
+	pub fn new(conf: KbctConf, key_code: impl Fn(&String) -> Option<i32>) -> Result<Kbct> {
+		let simple = conf.keymap.unwrap_or_default();
+		let complex = conf.layers.unwrap_or_default();
+		let combos = conf.combos.unwrap_or_default();
+		let leader = conf.leader;
+		let caps_word_conf = conf.caps_word.unwrap_or_default();
+		let auto_shift_conf = conf.auto_shift;
+		let disabled = conf.disabled.unwrap_or_default();
+		let suppress_conf = conf.suppress_while_typing;
+		let axes_conf = conf.axes.unwrap_or_default();
+		let leader_conf_sequences = leader.iter().flat_map(|x| x.sequences.iter());
+		// The keys of the default input method are only resolved when a unicode target is used
+		let unicode_input = conf.unicode_input;
+		let default_unicode_input = KbctUnicodeConf::ibus();
+
+		let mut layer_index = HashMap::new();
+		for (i, layer) in complex.iter().enumerate() {
+			if let Some(name) = &layer.name {
+				if layer_index.insert(name, i).is_some() {
+					return Err(KbctError::Error(format!("Duplicate layer: {:?}", name)));
+				}
+			} else if layer.modifiers.is_empty() {
+				return Err(KbctError::Error(
+					"Layers without modifiers have to be named".to_string(),
+				));
+			}
+		}
+
+		let context = KbctConfContext {
+			layer_index,
+			unicode_input: unicode_input.as_ref().unwrap_or(&default_unicode_input),
+		};
+
+		// Mouse motions are resolved along with the keys of the keyboard
+		let key_code = |k: &String| {
+			MouseMotion::from_name(k)
+				.map(MouseMotion::code)
+				.or_else(|| key_code(k))
+		};
+		let str_to_code = |k| key_code(k).unwrap();
+		let str_to_code_pair = |(k, v): (_, &KeyPressConf)| -> Result<(Keycode, KeyAction)> {
+			Ok((str_to_code(k), v.key_action(&key_code, &context)?))
+		};
+
+		let all_keys = simple
+			.iter()
+			.flat_map(|(k, v)| std::iter::once(k).chain(v.all_keys()))
+			.chain(complex.iter().flat_map(|x| {
+				x.modifiers.iter().chain(x.without.iter()).chain(
+					x.keymap
+						.iter()
+						.flat_map(|(k, v)| std::iter::once(k).chain(v.all_keys())),
+				)
+			}))
+			.chain(
+				combos
+					.iter()
+					.flat_map(|x| x.keys.iter().chain(x.target.all_keys())),
+			)
+			.chain(unicode_input.iter().flat_map(|x| x.all_keys()))
+			.chain(
+				leader_conf_sequences
+					.clone()
+					.flat_map(|x| x.keys.iter().chain(x.target.all_keys())),
+			)
+			.chain(
+				caps_word_conf
+					.keys
+					.iter()
+					.flatten()
+					.flat_map(|(k, v)| std::iter::once(k).chain(v.all_keys())),
+			)
+			.chain(auto_shift_conf.iter().flat_map(|x| x.keys.iter()))
+			.chain(disabled.iter())
+			.chain(suppress_conf.iter().flat_map(|x| x.keys.iter()));
+
+		let unknown_keys: BTreeSet<&String> = all_keys.filter(|x| key_code(x).is_none()).collect();
+		if !unknown_keys.is_empty() {
+			return Err(KbctError::Error(format!(
+				"Configuration contains unknown keys: {:?}",
+				unknown_keys
+			)));
+		}
+
+		let simple_map: KeyMap = simple.iter().map(str_to_code_pair).collect::<Result<_>>()?;
+
+		let layers: Layers = complex
+			.iter()
 			.map(|x| {
+				Ok(KbctLayer {
+					priority: x.priority,
+					modifiers: x.modifiers.iter().map(str_to_code).collect(),
+					exact: x.exact,
+					without: x.without.iter().map(str_to_code).collect(),
+					match_on: x.match_on,
+					keymap: x
+						.keymap
+						.iter()
+						.map(str_to_code_pair)
+						.collect::<Result<_>>()?,
+					one_shot: x.one_shot,
+					one_shot_timeout: x.one_shot_timeout,
+					led: x.led,
+				})
+			})
+			.collect::<Result<_>>()?;
+
+		let combos = combos
+			.iter()
+			.map(|x| {
+				if x.keys.len() < 2 {
+					return Err(KbctError::Error(format!(
+						"Combos need at least two keys: {:?}",
+						x.keys
+					)));
+				}
+				Ok(KbctCombo {
+					keys: x.keys.iter().map(str_to_code).collect(),
+					target: x.target.key_press(&key_code)?,
+					timeout: x.timeout.unwrap_or(KbctCombo::DEFAULT_TIMEOUT),
+				})
+			})
+			.collect::<Result<_>>()?;
+
+		let mut leader_sequences = KbctLeaderTrie::default();
+		for sequence in leader_conf_sequences {
+			if sequence.keys.is_empty() {
+				return Err(KbctError::Error(
+					"Leader sequences need at least one key".to_string(),
+				));
+			}
+			let keys: Vec<Keycode> = sequence.keys.iter().map(str_to_code).collect();
+			let action = sequence.target.key_action(&key_code, &context)?;
+			if !leader_sequences.insert(&keys, action) {
+				return Err(KbctError::Error(format!(
+					"Duplicate leader sequence: {:?}",
+					sequence.keys
+				)));
+			}
+		}
+		let leader_timeout = leader
+			.as_ref()
+			.and_then(|x| x.timeout)
+			.unwrap_or(KbctLeaderTrie::DEFAULT_TIMEOUT);
+
+		// Digits, backspace, delete and underscore continue the word by default
+		let caps_word_keys = match &caps_word_conf.keys {
+			Some(keys) => keys
+				.iter()
+				.map(|(k, v)| Ok((str_to_code(k), v.key_press(&key_code)?)))
+				.collect::<Result<_>>()?,
+			None => ('0'..='9')
+				.map(|x| x.to_string())
+				.chain(vec!["backspace".to_string(), "delete".to_string()])
+				.filter_map(|x| key_code(&x))
+				.map(|code| {
+					let key_press = KeyPress {
+						code,
+						modifiers: Default::default(),
+						chord: vec![],
+						suppressed: Default::default(),
+					};
+					(code, key_press)
+				})
+				.chain(type_char('_', &key_code).ok().map(|x| (x.code, x)))
+				.collect(),
+		};
+		let caps_word = KbctCapsWord {
+			letters: ('a'..='z')
+				.filter_map(|x| key_code(&x.to_string()))
+				.collect(),
+			shift: key_code(&"leftshift".to_string()),
+			keys: caps_word_keys,
+			timeout: caps_word_conf
+				.timeout
+				.unwrap_or(KbctCapsWord::DEFAULT_TIMEOUT),
+		};
+
+		let auto_shift = KbctAutoShift {
+			keys: auto_shift_conf
+				.iter()
+				.flat_map(|x| x.keys.iter().map(str_to_code))
+				.collect(),
+			shift: key_code(&"leftshift".to_string()),
+			timeout: auto_shift_conf
+				.as_ref()
+				.and_then(|x| x.timeout)
+				.unwrap_or(KbctAutoShift::DEFAULT_TIMEOUT),
+		};
+
+		let disabled_keys = disabled.iter().map(str_to_code).collect();
+		let suppress_while_typing = KbctSuppressWhileTyping {
+			keys: suppress_conf
+				.iter()
+				.flat_map(|x| x.keys.iter().map(str_to_code))
+				.collect(),
+			timeout: suppress_conf
+				.as_ref()
+				.and_then(|x| x.timeout)
+				.unwrap_or(KbctSuppressWhileTyping::DEFAULT_TIMEOUT),
+		};
+
+		let modifier_codes = MODIFIER_KEYS
+			.iter()
+			.filter_map(|x| key_code(&x.to_string()))
+			.collect();
+
+		let axes = axes_conf
+			.into_iter()
+			.map(|(axis, x)| {
+				let scale = x.scale.unwrap_or(1.0) * if x.invert { -1.0 } else { 1.0 };
 				(
-					x.modifiers.iter().map(str_to_code).collect(),
-					x.keymap.iter().map(str_to_code_pair).collect(),
+					axis,
+					KbctAxis {
+						axis: x.axis.unwrap_or(axis),
+						scale,
+					},
 				)
 			})
 			.collect();
 
 		Ok(Kbct {
 			simple_map,
-			complex_map,
+			layers,
+			combos,
+			modifier_codes,
 			source_to_mapped: LinkedHashMap::new(),
 			mapped_to_source: hashmap!(),
 			transient_modifiers: Default::default(),
+			combo_events: vec![],
+			held_combos: vec![],
+			resolved_actions: Default::default(),
+			pending_key: None,
+			tap_dance: None,
+			leader_sequences,
+			leader_timeout,
+			leader: None,
+			caps_word,
+			caps_word_deadline: None,
+			auto_shift,
+			auto_shift_key: None,
+			disabled_keys,
+			suppress_while_typing,
+			suppressed_keys: Default::default(),
+			last_typed: Default::default(),
+			buffered_events: Default::default(),
+			held_one_shots: Default::default(),
+			one_shot: Default::default(),
+			layer_keys: Default::default(),
+			locked_layers: Default::default(),
+			swallowed_keys: Default::default(),
+			tap_alone_keys: Default::default(),
+			lifted_modifiers: Default::default(),
+			axes,
+			axis_remainders: Default::default(),
+			last_clicked: None,
 			logic_clock: 0,
+			now: 0,
 		})
 	}
 
-	fn get_active_complex_modifiers(&self) -> Option<(&KeySet, &KeyMap)> {
-		let cm = &self.complex_map;
+	// Returns the held keys along with their matched codes that are one of the given layer keys
+	fn get_held_layer_keys(&self, layer: &KbctLayer, codes: &KeySet) -> Vec<(Keycode, Keycode)> {
+		self.source_to_mapped
+			.iter()
+			.map(|(source, state)| (*source, layer.match_code(*source, state.mapped_code)))
+			.filter(|(_, code)| codes.contains(code))
+			.collect()
+	}
+
+	// Returns the held keys that activate a layer if all of its modifiers are held
+	fn get_layer_sources(&self, layer: &KbctLayer) -> Option<KeySet> {
+		let held = self.get_held_layer_keys(layer, &layer.modifiers);
+		let matched: KeySet = held.iter().map(|(_, code)| *code).collect();
+		if layer.modifiers.is_empty() || matched.len() != layer.modifiers.len() {
+			return None;
+		}
+		Some(held.into_iter().map(|(source, _)| source).collect())
+	}
+
+	// Returns the active layers by precedence along with the held modifiers that activate them
+	// Layers are ordered by priority, then by the number of modifiers and then by activation time
+	fn get_active_layers(&self) -> Vec<(KeySet, &KbctLayer)> {
 		let stm = &self.source_to_mapped;
 
-		let get_last_pressed_time = |s: &KeySet| -> u64 {
-			s.iter()
-				.map(|x| self.get_last_source_mapping_to(*x).unwrap())
-				.map(|x| stm.get(&x).unwrap().time)
-				.max()
-				.unwrap()
-		};
+		let get_last_pressed_time =
+			|s: &KeySet| -> u64 { s.iter().map(|x| stm.get(x).unwrap().time).max().unwrap() };
 
-		let latest_keystroke = |l: &&KeySet, r: &&KeySet| -> Ordering {
-			if l.len() == r.len() {
-				get_last_pressed_time(l).cmp(&get_last_pressed_time(r))
-			} else {
-				l.len().cmp(&r.len())
-			}
+		let other_modifiers_pressed = |x: &KeySet| {
+			stm.iter().any(|(source, state)| {
+				!x.contains(source) && self.is_modifier(*source, state.mapped_code)
+			})
 		};
 
-		let all_pressed = |x: &&KeySet| x.iter().find(|x| stm.get(x).is_none()).is_none();
+		let held_layers = self
+			.layers
+			.iter()
+			.enumerate()
+			.filter(|(_, x)| self.get_held_layer_keys(x, &x.without).is_empty())
+			.filter_map(|(i, x)| self.get_layer_sources(x).map(|sources| (i, x, sources)))
+			.filter(|(_, x, sources)| !x.exact || !other_modifiers_pressed(sources))
+			.map(|(i, _, sources)| {
+				let time = get_last_pressed_time(&sources);
+				(i, sources, time)
+			});
 
-		cm.iter()
-			.map(|(k, _v)| k)
-			.filter(all_pressed)
-			.max_by(latest_keystroke)
-			.map(|x| (x, cm.get(x).unwrap()))
+		let action_layers = self
+			.layer_keys
+			.values()
+			.filter(|(action, _)| action.mode == LayerMode::Momentary)
+			.map(|(action, time)| (action.layer, *time))
+			.chain(
+				self.locked_layers
+					.iter()
+					.map(|(layer, time)| (*layer, *time)),
+			)
+			.map(|(i, time)| (i, KeySet::new(), time));
+
+		let mut active: Vec<_> = held_layers.chain(action_layers).collect();
+		active.sort_by_key(|(i, modifiers, time)| {
+			Reverse((self.layers[*i].priority, modifiers.len(), *time))
+		});
+
+		let mut seen = HashSet::new();
+		active
+			.into_iter()
+			.filter(|(i, _, _)| seen.insert(*i))
+			.map(|(i, modifiers, _)| (modifiers, &self.layers[i]))
+			.collect()
 	}
 
-	fn make_ev(code: Keycode, ev_type: KbctKeyStatus) -> KbctEvent {
-		KbctEvent { code, ev_type }
+	// Applies a layer action on press of its key
+	fn press_layer_key(&mut self, source: Keycode, action: LayerAction) {
+		let time = self.logic_clock;
+		self.logic_clock += 1;
+		match action.mode {
+			LayerMode::Momentary => {}
+			LayerMode::Toggle => {
+				if self.locked_layers.remove(&action.layer).is_none() {
+					self.locked_layers.insert(action.layer, time);
+				}
+			}
+			LayerMode::To => {
+				self.locked_layers.clear();
+				self.locked_layers.insert(action.layer, time);
+			}
+		}
+		self.layer_keys.insert(source, (action, time));
+	}
+
+	fn make_ev(&self, code: Keycode, ev_type: KbctKeyStatus) -> KbctEvent {
+		KbctEvent::new(code, ev_type, self.now)
 	}
 
 	fn change_key_state(&mut self, source: Keycode, mapped: Keycode, status: KbctKeyStatus) {
@@ -241,135 +1683,1011 @@ impl Kbct {
 				.entry(mapped)
 				.or_insert(empty_hashet)
 				.insert(source, true);
+			let chord = self
+				.source_to_mapped
+				.get(&source)
+				.map(|x| x.chord.clone())
+				.unwrap_or_default();
 			self.source_to_mapped.insert(
 				source,
 				KbctKeyState {
 					time: self.logic_clock,
 					mapped_code: mapped,
+					chord,
 					status,
 				},
 			);
 		} else {
-			let set = self.mapped_to_source.entry(mapped).or_insert(empty_hashet);
-			set.remove(&source);
-			if set.is_empty() {
-				self.mapped_to_source.remove(&mapped);
+			let chord = self
+				.source_to_mapped
+				.remove(&source)
+				.map(|x| x.chord)
+				.unwrap_or_default();
+			for code in std::iter::once(mapped).chain(chord) {
+				let set = self.mapped_to_source.entry(code).or_default();
+				set.remove(&source);
+				if set.is_empty() {
+					self.mapped_to_source.remove(&code);
+				}
 			}
-
-			self.source_to_mapped.remove(&source);
 		}
 		self.logic_clock += 1;
 	}
 
-	fn get_last_source_mapping_to(&self, code: Keycode) -> Option<Keycode> {
-		match self.mapped_to_source.get(&code) {
-			Some(x) => x.iter().map(|x| *x.0).last(),
-			None => None,
+	// Records the codes held along with the mapped code of a pressed key
+	fn hold_chord(&mut self, source: Keycode, chord: &[Keycode]) {
+		for code in chord {
+			self.mapped_to_source
+				.entry(*code)
+				.or_default()
+				.insert(source, true);
+		}
+		self.source_to_mapped.get_mut(&source).unwrap().chord = chord.to_vec();
+	}
+
+	// Returns the action a key is mapped to and whether it comes from the active layer
+	fn get_key_action(&self, code: Keycode) -> (KeyAction, bool) {
+		if let Some(action) = self.resolved_actions.get(&code) {
+			return action.clone();
+		}
+
+		// Keys that are not defined or transparent fall through to the next active layer
+		let one_shot_layer = self.one_shot.layer.map(|(layer, _)| &self.layers[layer]);
+		let complex_action = one_shot_layer
+			.into_iter()
+			.chain(self.get_active_layers().into_iter().map(|(_, layer)| layer))
+			.flat_map(|layer| layer.keymap.get(&code))
+			.find(|action| !matches!(action, KeyAction::Transparent));
+		match complex_action {
+			Some(action) => (action.clone(), true),
+			None => {
+				let not_mapped = KeyAction::Press(KeyPress {
+					code,
+					modifiers: Default::default(),
+					chord: vec![],
+					suppressed: Default::default(),
+				});
+				let action = match self.simple_map.get(&code) {
+					Some(KeyAction::Transparent) | None => not_mapped,
+					Some(action) => action.clone(),
+				};
+				(action, false)
+			}
 		}
 	}
 
 	pub fn map_event(&mut self, ev: KbctEvent) -> Vec<KbctEvent> {
+		// Deadlines that passed before the event are handled first, in case the timer fired late
+		let mut result = self.on_timeout(ev.time);
+		if let KbctEventKind::Relative(value) = ev.kind {
+			result.extend(self.map_relative_event(ev, value));
+		} else if !self.is_dropped(&ev) {
+			result.extend(self.map_combo_event(ev));
+		}
+		result
+	}
+
+	// Wheel ticks are typed as wheel keys if those are mapped, other motions are moved along their mapped axis
+	// High resolution wheels are mapped like their wheel, so that the two report the same motion
+	fn map_relative_event(&mut self, ev: KbctEvent, value: i32) -> Vec<KbctEvent> {
 		use KbctKeyStatus::*;
-		let empty_map = hashmap!();
-		let empty_set = btreeset!();
+		let source = RelativeAxis::from_code(ev.code).unwrap();
+		let axis = source.low_res();
+		let wheel_code = axis.wheel_motion(value).map(MouseMotion::code);
+		if let Some(code) = wheel_code.filter(|x| self.is_mapped(*x)) {
+			// The ticks are typed once, for the events of the wheel
+			if source != axis {
+				return vec![];
+			}
+			let mut result = vec![];
+			for _ in 0..value.abs() {
+				result.extend(self.map_event(KbctEvent::new(code, Clicked, ev.time)));
+				result.extend(self.map_event(KbctEvent::new(code, Released, ev.time)));
+			}
+			return result;
+		}
 
-		let not_mapped = KeyPress {
-			code: ev.code,
-			modifiers: Default::default(),
+		let (target, scale) = match self.axes.get(&axis) {
+			Some(x) => (x.axis, x.scale),
+			None => (axis, 1.0),
 		};
-		let simple_mapped = self.simple_map.get(&ev.code).unwrap_or(&not_mapped);
-		let (active_modifiers, complex_keymap) = self
-			.get_active_complex_modifiers()
-			.unwrap_or((&empty_set, &empty_map));
-
-		let mut is_complex = true;
-		let complex_mapped = complex_keymap.get(&ev.code).unwrap_or_else(|| {
-			is_complex = false;
-			&simple_mapped
-		});
+		let target = match (source == axis, target.hi_res()) {
+			(true, _) => target,
+			(false, Some(hi_res)) => hi_res,
+			// A wheel moved along the pointer axes only moves them by ticks
+			(false, None) => return vec![],
+		};
+		let moved = self.move_axis(target, value as f64 * scale);
+		let mut result = vec![];
+		if moved != 0 {
+			result.push(KbctEvent::relative(target, moved, ev.time));
+			// A pointer axis moved along a wheel has no high resolution events of its own
+			if let (None, Some(hi_res)) = (axis.hi_res(), target.hi_res()) {
+				let hi_res_moved = moved * RelativeAxis::HI_RES_TICK;
+				result.push(KbctEvent::relative(hi_res, hi_res_moved, ev.time));
+			}
+		}
+		result
+	}
 
-		let prev_state = self.source_to_mapped.get(&ev.code);
-		let prev_status = prev_state.map(|x| x.status).unwrap_or(Released);
+	// Returns the whole part of the motion of an axis, the fraction is carried over to its next motion
+	fn move_axis(&mut self, axis: RelativeAxis, distance: f64) -> i32 {
+		let remainder = self.axis_remainders.remove(&axis).unwrap_or_default();
+		let distance = remainder + distance;
+		let moved = distance.trunc();
+		self.axis_remainders.insert(axis, distance - moved);
+		moved as i32
+	}
+
+	// Whether a key is mapped to anything else than itself in the keymap or the active layers
+	fn is_mapped(&self, code: Keycode) -> bool {
+		match self.get_key_action(code).0 {
+			KeyAction::Press(key_press) => {
+				key_press.code != code
+					|| !key_press.modifiers.is_empty()
+					|| !key_press.chord.is_empty()
+			}
+			_ => true,
+		}
+	}
+
+	// Disabled keys and keys suppressed while typing are dropped before any other stage
+	fn is_dropped(&mut self, ev: &KbctEvent) -> bool {
+		use KbctKeyStatus::*;
+		if self.disabled_keys.contains(&ev.code) {
+			return true;
+		}
+		let suppress = &self.suppress_while_typing;
+		if !suppress.keys.contains(&ev.code) {
+			let typed = ev.code < KbctSuppressWhileTyping::FIRST_BUTTON_CODE
+				&& !self.modifier_codes.contains(&ev.code);
+			if ev.ev_type == Clicked && typed {
+				self.last_typed.set(Some(ev.time));
+			}
+			return false;
+		}
+		match ev.ev_type {
+			Clicked => {
+				let typing = self
+					.last_typed
+					.get()
+					.is_some_and(|x| ev.time < x + suppress.timeout);
+				if typing {
+					self.suppressed_keys.insert(ev.code);
+				}
+				typing
+			}
+			Released => self.suppressed_keys.remove(&ev.code),
+			_ => self.suppressed_keys.contains(&ev.code),
+		}
+	}
+
+	// Maps the events that passed the combo stage
+	fn map_source_event(&mut self, ev: KbctEvent) -> Vec<KbctEvent> {
+		if self.auto_shift_key.is_some() {
+			self.map_auto_shift_event(ev)
+		} else if self.leader.is_some() {
+			self.map_leader_event(ev)
+		} else if self.tap_dance.is_some() {
+			self.map_tap_dance_event(ev)
+		} else if self.pending_key.is_some() {
+			self.map_pending_event(ev)
+		} else {
+			self.map_key_event(ev)
+		}
+	}
+
+	// Combos that can still be completed with the buffered keys and a new one
+	fn get_combo_candidates(&self, code: Option<Keycode>) -> impl Iterator<Item = &KbctCombo> {
+		let keys: KeySet = self
+			.combo_events
+			.iter()
+			.map(|x| x.code)
+			.chain(code)
+			.collect();
+		self.combos
+			.iter()
+			.filter(move |combo| combo.keys.is_superset(&keys))
+	}
+
+	fn combo_deadline(&self) -> Option<Timestamp> {
+		let first = self.combo_events.first()?;
+		let timeout = self.get_combo_candidates(None).map(|x| x.timeout).max()?;
+		Some(first.time + timeout)
+	}
+
+	fn pending_key_deadline(&self) -> Option<Timestamp> {
+		self.pending_key
+			.as_ref()
+			.map(|pending| pending.time + pending.tap_hold.timeout)
+	}
+
+	fn tap_dance_deadline(&self) -> Option<Timestamp> {
+		self.tap_dance
+			.as_ref()
+			.map(|dance| dance.time + dance.tap_dance.timeout)
+	}
+
+	fn auto_shift_deadline(&self) -> Option<Timestamp> {
+		self.auto_shift_key
+			.as_ref()
+			.map(|key| key.time + self.auto_shift.timeout)
+	}
+
+	// The earliest time at which `on_timeout` has to be called, if there is any
+	pub fn next_deadline(&self) -> Option<Timestamp> {
+		self.combo_deadline()
+			.into_iter()
+			.chain(self.pending_key_deadline())
+			.chain(self.tap_dance_deadline())
+			.chain(self.leader.as_ref().map(|x| x.deadline))
+			.chain(self.caps_word_deadline)
+			.chain(self.auto_shift_deadline())
+			.chain(self.one_shot.deadline())
+			.min()
+	}
+
+	// The codes of the keys the mapping can write, besides the keys it passes through
+	pub fn output_codes(&self) -> KeySet {
+		let actions = self
+			.simple_map
+			.values()
+			.chain(self.layers.iter().flat_map(|x| x.keymap.values()))
+			.chain(self.leader_sequences.actions());
+		actions
+			.flat_map(|x| x.output_codes())
+			.chain(self.combos.iter().flat_map(|x| x.target.output_codes()))
+			.chain(self.caps_word.keys.values().flat_map(|x| x.output_codes()))
+			.chain(self.caps_word.shift)
+			.chain(self.auto_shift.shift)
+			.collect()
+	}
+
+	// Keys are suppressed while typing on any of the keyboards sharing the time
+	pub fn share_typing_time(&mut self, last_typed: KbctTypingTime) {
+		self.last_typed = last_typed;
+	}
+
+	// The LEDs of the active layers
+	pub fn active_leds(&self) -> HashSet<Led> {
+		self.get_active_layers()
+			.into_iter()
+			.filter_map(|(_, layer)| layer.led)
+			.collect()
+	}
+
+	pub fn on_timeout(&mut self, now: Timestamp) -> Vec<KbctEvent> {
+		self.now = self.now.max(now);
 		let mut result = vec![];
+		while let Some(deadline) = self.next_deadline() {
+			if deadline > now {
+				break;
+			}
+			if self.combo_deadline() == Some(deadline) {
+				result.extend(self.resolve_combo());
+			} else if self.pending_key_deadline() == Some(deadline) {
+				result.extend(self.resolve_pending_key(true));
+			} else if self.tap_dance_deadline() == Some(deadline) {
+				result.extend(self.resolve_tap_dance());
+			} else if self.leader.as_ref().map(|x| x.deadline) == Some(deadline) {
+				result.extend(self.resolve_leader());
+			} else if self.auto_shift_deadline() == Some(deadline) {
+				result.extend(self.resolve_auto_shift(true));
+			} else if self.caps_word_deadline == Some(deadline) {
+				self.caps_word_deadline = None;
+			} else {
+				self.one_shot.expire(deadline);
+			}
+		}
+		result
+	}
 
-		match (prev_status, ev.ev_type) {
-			(Released, Clicked) => {
-				let mut synthetic_modifier_events: Vec<_> = active_modifiers
+	fn is_modifier(&self, source: Keycode, mapped: Keycode) -> bool {
+		self.modifier_codes.contains(&mapped)
+			|| self
+				.layers
+				.iter()
+				.any(|x| x.modifiers.contains(&x.match_code(source, mapped)))
+	}
+
+	// Whether a modifier is held or a one-shot modifier is armed, such as for a shortcut
+	fn is_modifier_active(&self) -> bool {
+		!self.one_shot.modifiers.is_empty()
+			|| self
+				.source_to_mapped
+				.iter()
+				.any(|(source, state)| self.is_modifier(*source, state.mapped_code))
+	}
+
+	// Arms a one-shot modifier, tapping it again while armed cancels it
+	fn arm_one_shot(&mut self, one_shot: OneShot) {
+		if self.one_shot.modifiers.remove(&one_shot.modifier).is_none() {
+			let deadline = one_shot.timeout.map(|x| self.now + x);
+			self.one_shot.modifiers.insert(one_shot.modifier, deadline);
+		}
+	}
+
+	// Arms a one-shot layer if the released key was part of its modifiers and no other key was clicked
+	fn arm_one_shot_layer(&mut self, source: Keycode) {
+		if self.last_clicked != Some(source) {
+			return;
+		}
+		let layer = self
+			.layers
+			.iter()
+			.enumerate()
+			.filter(|(_, x)| x.one_shot)
+			.filter(|(_, x)| {
+				self.get_layer_sources(x)
+					.is_some_and(|s| s.contains(&source))
+			})
+			.max_by_key(|(_, x)| x.modifiers.len())
+			.map(|(i, x)| (i, x.one_shot_timeout));
+		if let Some((layer, timeout)) = layer {
+			if self.one_shot.layer.is_some_and(|(x, _)| x == layer) {
+				self.one_shot.layer = None;
+			} else {
+				self.one_shot.layer = Some((layer, timeout.map(|x| self.now + x)));
+			}
+		}
+	}
+
+	fn map_pending_event(&mut self, ev: KbctEvent) -> Vec<KbctEvent> {
+		use KbctKeyStatus::*;
+		let pending = self.pending_key.as_ref().unwrap();
+		let tap_hold = &pending.tap_hold;
+
+		let is_hold = if ev.code == pending.source {
+			match ev.ev_type {
+				Released => Some(false),
+				_ => None,
+			}
+		} else {
+			match ev.ev_type {
+				Clicked if tap_hold.hold_on_other_key_press => Some(true),
+				Released if tap_hold.permissive_hold => self
+					.buffered_events
 					.iter()
-					.flat_map(|modifier_raw| {
-						let modifier_mapped = self.source_to_mapped.get(&modifier_raw).unwrap();
-
-						match (modifier_mapped.status, is_complex) {
-							(Clicked, true) => {
-								Some((*modifier_raw, modifier_mapped.mapped_code, ForceReleased))
-							}
-							(ForceReleased, false) => {
-								Some((*modifier_raw, modifier_mapped.mapped_code, Clicked))
-							}
-							(Released, _) => panic!("Illegal state"),
-							_ => None,
-						}
-					})
-					.collect();
+					.find(|x| x.code == ev.code && x.ev_type == Clicked)
+					.map(|_| true),
+				_ => None,
+			}
+		};
+
+		// The autorepeat of the pending key itself is dropped
+		if ev.code != pending.source || ev.ev_type != Pressed {
+			self.buffered_events.push_back(ev);
+		}
+
+		match is_hold {
+			Some(is_hold) => self.resolve_pending_key(is_hold),
+			None => vec![],
+		}
+	}
+
+	fn resolve_pending_key(&mut self, is_hold: bool) -> Vec<KbctEvent> {
+		let pending = self.pending_key.take().unwrap();
+		let action = if is_hold {
+			*pending.tap_hold.hold
+		} else {
+			KeyAction::Press(pending.tap_hold.tap)
+		};
+
+		let mut result = match action {
+			KeyAction::Press(key_press) => {
+				self.press_key(pending.source, &key_press, pending.is_complex)
+			}
+			// Like a resolved tap dance, the key is mapped again with the hold action
+			action => {
+				self.resolved_actions
+					.insert(pending.source, (action, pending.is_complex));
+				self.map_key_event(self.make_ev(pending.source, KbctKeyStatus::Clicked))
+			}
+		};
+		for ev in std::mem::take(&mut self.buffered_events) {
+			result.extend(self.on_timeout(ev.time));
+			result.extend(self.map_source_event(ev));
+		}
+		result
+	}
+
+	// Any other event resolves the held auto-shift key as not shifted
+	fn map_auto_shift_event(&mut self, ev: KbctEvent) -> Vec<KbctEvent> {
+		use KbctKeyStatus::*;
+		let source = self.auto_shift_key.as_ref().unwrap().source;
+		if ev.code == source && ev.ev_type == Pressed {
+			// The autorepeat is dropped until it is known whether the key is shifted
+			return vec![];
+		}
+		let mut result = self.resolve_auto_shift(false);
+		result.extend(self.map_source_event(ev));
+		result
+	}
 
-				let mapped_code = complex_mapped.code;
-				// Skip transient modifiers that are already being held
-				let transient_modifiers: KeySet = complex_mapped
-					.modifiers
+	// A shifted key is tapped right away and does not repeat, the rest of its events are dropped
+	fn resolve_auto_shift(&mut self, shifted: bool) -> Vec<KbctEvent> {
+		use KbctKeyStatus::*;
+		let key = self.auto_shift_key.take().unwrap();
+		let mut key_press = key.key_press;
+		if !shifted {
+			return self.press_key(key.source, &key_press, key.is_complex);
+		}
+		key_press.modifiers.extend(self.auto_shift.shift);
+		let mut result = self.press_key(key.source, &key_press, key.is_complex);
+		result.extend(self.map_key_event(self.make_ev(key.source, Released)));
+		self.swallowed_keys.insert(key.source);
+		result
+	}
+
+	// Swallows the keys pressed after the leader key, keys held before it are passed on
+	fn map_leader_event(&mut self, ev: KbctEvent) -> Vec<KbctEvent> {
+		use KbctKeyStatus::*;
+		let leader = self.leader.as_mut().unwrap();
+		match ev.ev_type {
+			Clicked => {
+				leader.events.push(ev);
+				leader.held_keys.insert(ev.code);
+				leader.deadline = ev.time + self.leader_timeout;
+			}
+			Released if leader.held_keys.remove(&ev.code) => leader.events.push(ev),
+			// The autorepeat of swallowed keys is dropped
+			Pressed if leader.held_keys.contains(&ev.code) => {}
+			_ => return self.map_key_event(ev),
+		}
+
+		let leader = self.leader.as_ref().unwrap();
+		match self.leader_sequences.get(leader.keys()) {
+			// Wait for the timeout if a longer sequence can still be typed
+			Some(node) if node.action.is_some() && node.children.is_empty() => {
+				self.resolve_leader()
+			}
+			Some(_) => vec![],
+			None => self.resolve_leader(),
+		}
+	}
+
+	// Fires the sequence of the swallowed keys, or replays them if there is none
+	fn resolve_leader(&mut self) -> Vec<KbctEvent> {
+		use KbctKeyStatus::*;
+		let leader = self.leader.take().unwrap();
+		let action = self
+			.leader_sequences
+			.get(leader.keys())
+			.and_then(|x| x.action.clone());
+		let mut result = vec![];
+		match action {
+			Some(action) => {
+				// The action stands for the last key, the other ones are dropped until they are released
+				let last = leader.keys().last().unwrap();
+				let start = leader
+					.events
 					.iter()
-					.copied()
-					.filter(|code| {
-						self.mapped_to_source
-							.get(code)
-							.map_or(true, |x| x.is_empty())
-					})
-					.collect();
+					.rposition(|x| x.code == last && x.ev_type == Clicked)
+					.unwrap();
+				self.resolved_actions.insert(last, (action, false));
+				for ev in leader.events[start..].iter() {
+					if ev.code == last {
+						result.extend(self.map_source_event(*ev));
+					}
+				}
+				self.swallowed_keys
+					.extend(leader.held_keys.iter().filter(|x| **x != last).copied());
+			}
+			None => {
+				for ev in leader.events {
+					result.extend(self.on_timeout(ev.time));
+					result.extend(self.map_source_event(ev));
+				}
+			}
+		}
+		result
+	}
+
+	// Counts the taps of the dancing key, any other key ends the dance
+	fn map_tap_dance_event(&mut self, ev: KbctEvent) -> Vec<KbctEvent> {
+		use KbctKeyStatus::*;
+		let dance = self.tap_dance.as_mut().unwrap();
+		if ev.code != dance.source {
+			let mut result = self.resolve_tap_dance();
+			result.extend(self.map_source_event(ev));
+			return result;
+		}
+
+		match ev.ev_type {
+			Clicked => {
+				dance.taps += 1;
+				dance.held = true;
+				dance.time = ev.time;
+			}
+			Released => {
+				dance.held = false;
+				dance.time = ev.time;
+			}
+			Pressed => {}
+			ForceReleased => panic!("Illegal state"),
+		}
+
+		if dance.is_complete() {
+			return self.resolve_tap_dance();
+		}
+		vec![]
+	}
 
-				for (source, mapped, status) in synthetic_modifier_events.iter() {
-					self.change_key_state(*source, *mapped, *status)
+	// Presses the action of the number of taps, and releases it if the key is not held anymore
+	fn resolve_tap_dance(&mut self) -> Vec<KbctEvent> {
+		use KbctKeyStatus::*;
+		let dance = self.tap_dance.take().unwrap();
+		let actions = dance.tap_dance.actions;
+		let action = actions[dance.taps.min(actions.len()) - 1].clone();
+		let action = match action {
+			KeyAction::TapHold(tap_hold) if dance.held => *tap_hold.hold,
+			KeyAction::TapHold(tap_hold) => KeyAction::Press(tap_hold.tap),
+			action => action,
+		};
+		self.resolved_actions
+			.insert(dance.source, (action, dance.is_complex));
+
+		let mut result = self.map_source_event(self.make_ev(dance.source, Clicked));
+		if !dance.held {
+			result.extend(self.map_source_event(self.make_ev(dance.source, Released)));
+		}
+		result
+	}
+
+	fn map_combo_event(&mut self, ev: KbctEvent) -> Vec<KbctEvent> {
+		use KbctKeyStatus::*;
+
+		if let Some(i) = self
+			.held_combos
+			.iter()
+			.position(|x| x.held_keys.contains(&ev.code))
+		{
+			return self.map_held_combo_event(i, ev);
+		}
+
+		if !self.combo_events.is_empty() {
+			let is_buffered = self.combo_events.iter().any(|x| x.code == ev.code);
+			match ev.ev_type {
+				Clicked if self.get_combo_candidates(Some(ev.code)).next().is_some() => {
+					self.combo_events.push(ev);
+					// Fire right away unless a longer combo can still be completed
+					let keys: KeySet = self.combo_events.iter().map(|x| x.code).collect();
+					if self.get_combo_candidates(None).all(|x| x.keys == keys) {
+						return self.resolve_combo();
+					}
+					return vec![];
 				}
-				self.change_key_state(ev.code, mapped_code, Clicked);
-
-				// Release old transient modifiers and press new ones
-				// The state is not updated, the transient modifiers are released on the next key event
-				synthetic_modifier_events.extend(
-					std::mem::take(&mut self.transient_modifiers)
-						.into_iter()
-						.map(|code| (code, code, Released)),
-				);
-				synthetic_modifier_events.extend(
-					transient_modifiers
-						.iter()
-						.map(|code| (*code, *code, Clicked)),
-				);
-				self.transient_modifiers = transient_modifiers;
+				// The autorepeat of buffered keys is dropped
+				Pressed if is_buffered => return vec![],
+				_ => {
+					let mut result = self.resolve_combo();
+					result.extend(self.map_combo_event(ev));
+					return result;
+				}
+			}
+		}
 
-				result = synthetic_modifier_events
-					.iter()
-					.map(|(_s, target, st)| Kbct::make_ev(*target, *st))
-					.collect();
-				result.push(Kbct::make_ev(mapped_code, Clicked));
+		if ev.ev_type == Clicked && self.combos.iter().any(|x| x.keys.contains(&ev.code)) {
+			self.combo_events.push(ev);
+			return vec![];
+		}
+
+		self.map_source_event(ev)
+	}
+
+	// The output of a combo is released as soon as one of its keys is released
+	fn map_held_combo_event(&mut self, i: usize, ev: KbctEvent) -> Vec<KbctEvent> {
+		use KbctKeyStatus::*;
+		let combo = &mut self.held_combos[i];
+		let source = combo.source;
+		let mut result = vec![];
+		match ev.ev_type {
+			Released => {
+				combo.held_keys.remove(&ev.code);
+				let released = std::mem::replace(&mut combo.released, true);
+				if combo.held_keys.is_empty() {
+					self.held_combos.remove(i);
+				}
+				if !released {
+					result = self.map_source_event(KbctEvent { code: source, ..ev });
+				}
 			}
+			Pressed if !combo.released && ev.code == source => {
+				result = self.map_source_event(ev);
+			}
+			_ => {}
+		}
+		result
+	}
+
+	// Fires the largest combo among the buffered keys, the remaining keys are passed on
+	fn resolve_combo(&mut self) -> Vec<KbctEvent> {
+		use KbctKeyStatus::*;
+		let events = std::mem::take(&mut self.combo_events);
+		let keys: KeySet = events.iter().map(|x| x.code).collect();
+		let combo = self
+			.combos
+			.iter()
+			.filter(|x| x.keys.is_subset(&keys))
+			.max_by_key(|x| x.keys.len())
+			.cloned();
+
+		let mut result = vec![];
+		let (combo_events, rest): (Vec<_>, Vec<_>) = events
+			.into_iter()
+			.partition(|x| combo.as_ref().is_some_and(|c| c.keys.contains(&x.code)));
+		if let Some(combo) = combo {
+			let source = combo_events.first().unwrap().code;
+			let time = combo_events.last().unwrap().time;
+			self.held_combos.push(KbctHeldCombo {
+				source,
+				held_keys: combo.keys.clone(),
+				released: false,
+			});
+			self.resolved_actions
+				.insert(source, (KeyAction::Press(combo.target), false));
+			result.extend(self.map_source_event(KbctEvent::new(source, Clicked, time)));
+		}
+		for ev in rest {
+			result.extend(self.map_source_event(ev));
+		}
+		result
+	}
+
+	// Layer modifiers are released for keys mapped by a layer and pressed back for other keys
+	fn get_layer_modifier_events(
+		&self,
+		is_complex: bool,
+	) -> Vec<(Keycode, Keycode, KbctKeyStatus)> {
+		use KbctKeyStatus::*;
+		let active_modifiers: KeySet = self
+			.get_active_layers()
+			.into_iter()
+			.flat_map(|(modifiers, _)| modifiers)
+			.collect();
+
+		active_modifiers
+			.iter()
+			.flat_map(|modifier_raw| {
+				let modifier_mapped = self.source_to_mapped.get(modifier_raw).unwrap();
+
+				match (modifier_mapped.status, is_complex) {
+					(Clicked, true) => {
+						Some((*modifier_raw, modifier_mapped.mapped_code, ForceReleased))
+					}
+					(ForceReleased, false) => {
+						Some((*modifier_raw, modifier_mapped.mapped_code, Clicked))
+					}
+					(Released, _) => panic!("Illegal state"),
+					_ => None,
+				}
+			})
+			.collect()
+	}
+
+	// Taps the keys of a macro, each one delayed from the previous one
+	// Unlike other keys, the macro does not depend on how long its key is held
+	fn play_macro(
+		&mut self,
+		source: Keycode,
+		key_macro: &Macro,
+		is_complex: bool,
+	) -> Vec<KbctEvent> {
+		use KbctKeyStatus::*;
+		let layer_modifier_events = self.get_layer_modifier_events(is_complex);
+		for (source, mapped, status) in layer_modifier_events.iter() {
+			self.change_key_state(*source, *mapped, *status)
+		}
+		self.swallowed_keys.insert(source);
+		self.one_shot = Default::default();
+
+		let mut result: Vec<_> = layer_modifier_events
+			.iter()
+			.map(|(_, mapped, status)| self.make_ev(*mapped, *status))
+			.collect();
+		result.extend(
+			std::mem::take(&mut self.transient_modifiers)
+				.into_iter()
+				.map(|code| self.make_ev(code, Released)),
+		);
+
+		for (i, step) in key_macro.steps.iter().enumerate() {
+			let time = self.now + i as Timestamp * key_macro.delay;
+			result.extend(self.tap_key_press(step, time));
+		}
+		result
+	}
+
+	// Presses and releases a key along with its modifiers, the key state is not changed
+	fn tap_key_press(&self, key_press: &KeyPress, time: Timestamp) -> Vec<KbctEvent> {
+		use KbctKeyStatus::*;
+		let ev = |code, ev_type| KbctEvent::new(code, ev_type, time);
+		// Skip modifiers that are already being held, chords are tapped as a whole
+		let modifiers: Vec<Keycode> = key_press
+			.modifiers
+			.iter()
+			.chain(key_press.chord.iter())
+			.copied()
+			.filter(|code| self.mapped_to_source.get(code).is_none_or(|x| x.is_empty()))
+			.collect();
+		let mut result: Vec<_> = modifiers.iter().map(|code| ev(*code, Clicked)).collect();
+		result.push(ev(key_press.code, Clicked));
+		result.push(ev(key_press.code, Released));
+		result.extend(modifiers.iter().rev().map(|code| ev(*code, Released)));
+		result
+	}
+
+	// Shifts letters and maps the keys that continue the word while caps word is active
+	// Any other key that is not a modifier ends the word
+	fn apply_caps_word(&mut self, source: Keycode, key_press: &KeyPress) -> KeyPress {
+		let mut key_press = key_press.clone();
+		if self.caps_word_deadline.is_none() || self.is_modifier(source, key_press.code) {
+			return key_press;
+		}
+		let caps_word = &self.caps_word;
+		if caps_word.letters.contains(&key_press.code) {
+			key_press.modifiers.extend(caps_word.shift);
+		} else if let Some(target) = caps_word.keys.get(&key_press.code) {
+			key_press = target.clone();
+		} else {
+			self.caps_word_deadline = None;
+			return key_press;
+		}
+		self.caps_word_deadline = Some(self.now + caps_word.timeout);
+		key_press
+	}
+
+	fn press_key(
+		&mut self,
+		source: Keycode,
+		key_press: &KeyPress,
+		is_complex: bool,
+	) -> Vec<KbctEvent> {
+		use KbctKeyStatus::*;
+		let mut synthetic_modifier_events = self.get_layer_modifier_events(is_complex);
+		let key_press = &self.apply_caps_word(source, key_press);
+
+		let mapped_code = key_press.code;
+		let mut modifiers = key_press.modifiers.clone();
+		if !self.is_modifier(source, mapped_code) {
+			// Held one-shot keys act as plain modifiers, armed ones apply to this key only
+			synthetic_modifier_events.extend(self.held_one_shots.iter().flat_map(|(source, x)| {
+				match self.source_to_mapped.get(source) {
+					Some(state) if state.status == ForceReleased => {
+						Some((*source, x.modifier, Clicked))
+					}
+					_ => None,
+				}
+			}));
+			modifiers.extend(std::mem::take(&mut self.one_shot).modifiers.into_keys());
+		}
+
+		// Skip transient modifiers that are already being held
+		let transient_modifiers: KeySet = modifiers
+			.iter()
+			.copied()
+			.filter(|code| self.mapped_to_source.get(code).is_none_or(|x| x.is_empty()))
+			.collect();
+
+		// Modifiers lifted by other keys are pressed back, unless this key suppresses them as well
+		let mut lifted = vec![];
+		for modifier in std::mem::take(&mut self.lifted_modifiers)
+			.into_values()
+			.flatten()
+		{
+			let mapped = match self.source_to_mapped.get(&modifier) {
+				Some(state) if state.status == ForceReleased => state.mapped_code,
+				_ => continue,
+			};
+			if key_press.suppressed.contains(&mapped) {
+				lifted.push(modifier);
+			} else if !synthetic_modifier_events.iter().any(|x| x.0 == modifier) {
+				synthetic_modifier_events.push((modifier, mapped, Clicked));
+			}
+		}
+
+		// Suppressed modifiers are pressed back once the key is released or another key is pressed
+		let suppressed: Vec<(Keycode, Keycode)> = self
+			.source_to_mapped
+			.iter()
+			.filter(|(_, x)| x.status == Clicked && key_press.suppressed.contains(&x.mapped_code))
+			.map(|(source, x)| (*source, x.mapped_code))
+			.collect();
+		synthetic_modifier_events.extend(
+			suppressed
+				.iter()
+				.map(|(source, mapped)| (*source, *mapped, ForceReleased)),
+		);
+		lifted.extend(suppressed.into_iter().map(|x| x.0));
+		if !lifted.is_empty() {
+			self.lifted_modifiers.insert(source, lifted);
+		}
+
+		// Like transient modifiers, chord codes that are already being held are not clicked again
+		let chord: Vec<Keycode> = key_press
+			.chord
+			.iter()
+			.copied()
+			.filter(|code| self.mapped_to_source.get(code).is_none_or(|x| x.is_empty()))
+			.collect();
+
+		for (source, mapped, status) in synthetic_modifier_events.iter() {
+			self.change_key_state(*source, *mapped, *status)
+		}
+		self.change_key_state(source, mapped_code, Clicked);
+		self.hold_chord(source, &key_press.chord);
+
+		// Release old transient modifiers and press new ones
+		// The state is not updated, the transient modifiers are released on the next key event
+		synthetic_modifier_events.extend(
+			std::mem::take(&mut self.transient_modifiers)
+				.into_iter()
+				.map(|code| (code, code, Released)),
+		);
+		synthetic_modifier_events.extend(
+			transient_modifiers
+				.iter()
+				.map(|code| (*code, *code, Clicked)),
+		);
+		self.transient_modifiers = transient_modifiers;
+
+		let mut result: Vec<_> = synthetic_modifier_events
+			.iter()
+			.map(|(_s, target, st)| self.make_ev(*target, *st))
+			.collect();
+		result.extend(chord.iter().map(|code| self.make_ev(*code, Clicked)));
+		result.push(self.make_ev(mapped_code, Clicked));
+		result
+	}
+
+	// Presses back the modifiers suppressed by a released key, unless they were released meanwhile
+	fn restore_lifted_modifiers(&mut self, source: Keycode) -> Vec<KbctEvent> {
+		use KbctKeyStatus::*;
+		let mut result = vec![];
+		for modifier in self.lifted_modifiers.remove(&source).unwrap_or_default() {
+			let mapped = match self.source_to_mapped.get(&modifier) {
+				Some(state) if state.status == ForceReleased => state.mapped_code,
+				_ => continue,
+			};
+			self.change_key_state(modifier, mapped, Clicked);
+			result.push(self.make_ev(mapped, Clicked));
+		}
+		result
+	}
+
+	fn map_key_event(&mut self, ev: KbctEvent) -> Vec<KbctEvent> {
+		use KbctKeyStatus::*;
+
+		if self.layer_keys.contains_key(&ev.code) || self.swallowed_keys.contains(&ev.code) {
+			if ev.ev_type == Released {
+				self.layer_keys.remove(&ev.code);
+				self.swallowed_keys.remove(&ev.code);
+				self.resolved_actions.remove(&ev.code);
+			}
+			return vec![];
+		}
+
+		if ev.ev_type == Clicked {
+			self.last_clicked = Some(ev.code);
+		} else if ev.ev_type == Released {
+			self.resolved_actions.remove(&ev.code);
+			self.arm_one_shot_layer(ev.code);
+			if let Some(one_shot) = self.held_one_shots.remove(&ev.code) {
+				// A one-shot key that was used as a plain modifier is not armed
+				let unused = self
+					.source_to_mapped
+					.get(&ev.code)
+					.is_some_and(|x| x.status == ForceReleased);
+				if unused {
+					self.arm_one_shot(one_shot);
+				}
+			}
+		}
+
+		let prev_state = self.source_to_mapped.get(&ev.code);
+		let prev_status = prev_state.map(|x| x.status).unwrap_or(Released);
+		let mut result = vec![];
+
+		match (prev_status, ev.ev_type) {
+			(Released, Clicked) => match self.get_key_action(ev.code) {
+				(KeyAction::OneShot(one_shot), _) => {
+					// Not emitted unless another key is pressed while it is held
+					self.change_key_state(ev.code, one_shot.modifier, ForceReleased);
+					self.held_one_shots.insert(ev.code, one_shot);
+				}
+				(KeyAction::Layer(action), _) => self.press_layer_key(ev.code, action),
+				(KeyAction::Macro(key_macro), is_complex) => {
+					result = self.play_macro(ev.code, &key_macro, is_complex);
+				}
+				(KeyAction::Blocked, _) | (KeyAction::Transparent, _) => {
+					self.swallowed_keys.insert(ev.code);
+				}
+				(KeyAction::CapsWord, _) => {
+					self.swallowed_keys.insert(ev.code);
+					self.caps_word_deadline = match self.caps_word_deadline {
+						Some(_) => None,
+						None => Some(ev.time + self.caps_word.timeout),
+					};
+				}
+				(KeyAction::Leader, _) => {
+					self.swallowed_keys.insert(ev.code);
+					self.leader = Some(KbctLeader {
+						events: vec![],
+						held_keys: Default::default(),
+						deadline: ev.time + self.leader_timeout,
+					});
+				}
+				(KeyAction::Press(key_press), is_complex)
+					if self.auto_shift.keys.contains(&ev.code) && !self.is_modifier_active() =>
+				{
+					self.auto_shift_key = Some(KbctAutoShiftKey {
+						source: ev.code,
+						key_press,
+						is_complex,
+						time: ev.time,
+					});
+				}
+				(KeyAction::Press(key_press), is_complex) => {
+					result = self.press_key(ev.code, &key_press, is_complex);
+				}
+				(KeyAction::TapAlone(tap_alone), is_complex) => {
+					result = self.press_key(ev.code, &tap_alone.key, is_complex);
+					self.tap_alone_keys.insert(ev.code, (tap_alone, ev.time));
+				}
+				(KeyAction::TapDance(tap_dance), is_complex) => {
+					self.tap_dance = Some(KbctTapDance {
+						source: ev.code,
+						tap_dance,
+						is_complex,
+						taps: 1,
+						held: true,
+						time: ev.time,
+					});
+					if self.tap_dance.as_ref().unwrap().is_complete() {
+						result = self.resolve_tap_dance();
+					}
+				}
+				(KeyAction::TapHold(tap_hold), is_complex) => {
+					self.pending_key = Some(KbctPendingKey {
+						source: ev.code,
+						tap_hold,
+						is_complex,
+						time: ev.time,
+					});
+				}
+			},
 			(Clicked, Released) | (Pressed, Released) => {
-				if prev_state.is_none() {
-					warn!("WARNING: key press was not recorded, skipping");
-				} else {
-					let prev_mapped_code = prev_state.unwrap().mapped_code;
-					let down_keys = self
-						.mapped_to_source
-						.get(&prev_mapped_code)
-						.map(|x| x.len())
-						.unwrap_or(0);
-					if down_keys == 1 {
-						result.push(Kbct::make_ev(prev_mapped_code, Released));
+				if let Some(prev_state) = prev_state {
+					let prev_mapped_code = prev_state.mapped_code;
+					// Codes that are also held by other keys stay pressed
+					for code in
+						std::iter::once(&prev_mapped_code).chain(prev_state.chord.iter().rev())
+					{
+						let down_keys = self
+							.mapped_to_source
+							.get(code)
+							.map(|x| x.len())
+							.unwrap_or(0);
+						if down_keys == 1 {
+							result.push(self.make_ev(*code, Released));
+						}
 					}
 					// Release any pending transient modifiers
 					result.extend(
 						std::mem::take(&mut self.transient_modifiers)
 							.into_iter()
-							.map(|code| Kbct::make_ev(code, Released)),
+							.map(|code| self.make_ev(code, Released)),
 					);
 					self.change_key_state(ev.code, prev_mapped_code, Released);
+					result.extend(self.restore_lifted_modifiers(ev.code));
+					if let Some((tap_alone, time)) = self.tap_alone_keys.remove(&ev.code) {
+						let in_time = tap_alone.timeout.is_none_or(|x| ev.time <= time + x);
+						if self.last_clicked == Some(ev.code) && in_time {
+							result.extend(self.tap_key_press(&tap_alone.tap, self.now));
+						}
+					}
+				} else {
+					warn!("WARNING: key press was not recorded, skipping");
 				}
 			}
 			(ForceReleased, Released) => {
@@ -378,7 +2696,7 @@ impl Kbct {
 			}
 			(Clicked, Pressed) | (Pressed, Pressed) => {
 				let mapped = prev_state.unwrap().mapped_code;
-				result.push(Kbct::make_ev(mapped, Pressed));
+				result.push(self.make_ev(mapped, Pressed));
 			}
 			(ForceReleased, Pressed) => {}
 			_ => {
@@ -394,3 +2712,9 @@ impl Kbct {
 
 #[cfg(test)]
 mod test;
+
+// Tests refer to keys by the same names as configurations
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "util/keycodes.rs"]
+mod keycodes;
@@ -7,8 +7,8 @@ extern crate pretty_env_logger;
 extern crate uinput;
 extern crate uinput_sys;
 
-use std::{fs, fs::File, process, thread, time};
-use std::collections::{HashMap, HashSet};
+use std::{fs::File, process, time};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::os::unix::io::{AsRawFd, RawFd};
 
 use clap::Clap;
@@ -56,17 +56,287 @@ impl EventObserver for SignalReceiver {
 		Ok(ObserverResult::Terminate { status: 0 })
 	}
 
-	fn get_source_fd(&self) -> SourceFd {
+	fn get_source_fd(&self) -> SourceFd<'_> {
 		SourceFd(&self.raw_fd)
 	}
 }
 
+// Drops the presses of chattering switches, along with the rest of their events
+struct Debounce {
+	// Milliseconds after the release of a key within which a press of the same key is dropped
+	timeout: Timestamp,
+	last_released: HashMap<i32, Timestamp>,
+	dropped_keys: HashSet<i32>,
+}
+
+impl Debounce {
+	fn new(timeout: Timestamp) -> Debounce {
+		Debounce {
+			timeout,
+			last_released: HashMap::new(),
+			dropped_keys: HashSet::new(),
+		}
+	}
+
+	fn is_dropped(&mut self, ev: &KbctEvent) -> bool {
+		if ev.kind != KbctEventKind::Key {
+			return false;
+		}
+		match ev.ev_type {
+			KbctKeyStatus::Clicked => {
+				let bounced = self
+					.last_released
+					.get(&ev.code)
+					.is_some_and(|x| ev.time < x + self.timeout);
+				if bounced {
+					self.dropped_keys.insert(ev.code);
+				}
+				bounced
+			}
+			KbctKeyStatus::Released => {
+				self.last_released.insert(ev.code, ev.time);
+				self.dropped_keys.remove(&ev.code)
+			}
+			_ => self.dropped_keys.contains(&ev.code),
+		}
+	}
+}
+
+// Moves the pointer and scrolls the wheel while mouse keys are held
+struct MouseKeys {
+	conf: KbctMouseKeysConf,
+	timer: Timer,
+	held: HashSet<MouseMotion>,
+	// Time the first of the held keys was pressed, the pointer accelerates from then on
+	start: Timestamp,
+	last_tick: Timestamp,
+	// Fractions of pixels and wheel ticks that are carried over to the next tick, by axis
+	remainder: HashMap<i32, f64>,
+}
+
+impl MouseKeys {
+	const INTERVAL: u64 = 16;
+
+	fn new(conf: KbctMouseKeysConf) -> Result<MouseKeys> {
+		Ok(MouseKeys {
+			conf,
+			timer: Timer::new()?,
+			held: HashSet::new(),
+			start: 0,
+			last_tick: 0,
+			remainder: HashMap::new(),
+		})
+	}
+
+	// The relative axis a motion moves along and its direction
+	fn axis(motion: MouseMotion) -> (i32, f64) {
+		match motion {
+			MouseMotion::Up => (REL_Y, -1.0),
+			MouseMotion::Down => (REL_Y, 1.0),
+			MouseMotion::Left => (REL_X, -1.0),
+			MouseMotion::Right => (REL_X, 1.0),
+			MouseMotion::WheelUp => (REL_WHEEL, 1.0),
+			MouseMotion::WheelDown => (REL_WHEEL, -1.0),
+			MouseMotion::WheelLeft => (REL_HWHEEL, -1.0),
+			MouseMotion::WheelRight => (REL_HWHEEL, 1.0),
+		}
+	}
+
+	// Every press moves the pointer by at least one pixel or scrolls by one tick right away
+	fn press(&mut self, motion: MouseMotion, now: Timestamp) -> Result<()> {
+		if self.held.is_empty() {
+			self.start = now;
+			self.last_tick = now;
+			self.timer
+				.schedule_repeating(time::Duration::from_millis(MouseKeys::INTERVAL))?;
+		}
+		if self.held.insert(motion) {
+			let (axis, direction) = MouseKeys::axis(motion);
+			*self.remainder.entry(axis).or_default() += direction;
+		}
+		Ok(())
+	}
+
+	fn release(&mut self, motion: MouseMotion) -> Result<()> {
+		if self.held.remove(&motion) && self.held.is_empty() {
+			self.remainder.clear();
+			self.timer.cancel()?;
+		}
+		Ok(())
+	}
+
+	// Returns the relative events for the time that passed since the last tick
+	fn tick(&mut self, now: Timestamp) -> Vec<(i32, i32)> {
+		let elapsed = now.saturating_sub(self.last_tick) as f64 / 1000.0;
+		self.last_tick = now;
+		let pointer_speed = self.conf.pointer_speed(now.saturating_sub(self.start));
+		let wheel_speed = self.conf.wheel_speed();
+
+		let mut velocity: HashMap<i32, f64> = HashMap::new();
+		for motion in self.held.iter() {
+			let (axis, direction) = MouseKeys::axis(*motion);
+			let speed = match axis {
+				REL_WHEEL | REL_HWHEEL => wheel_speed,
+				_ => pointer_speed,
+			};
+			*velocity.entry(axis).or_default() += direction * speed;
+		}
+
+		let mut result = vec![];
+		for axis in [REL_X, REL_Y, REL_WHEEL, REL_HWHEEL] {
+			let velocity = velocity.get(&axis).copied().unwrap_or_default();
+			let distance =
+				self.remainder.get(&axis).copied().unwrap_or_default() + velocity * elapsed;
+			let value = distance.trunc();
+			self.remainder.insert(axis, distance - value);
+			if value != 0.0 {
+				result.push((axis, value as i32));
+			}
+		}
+		result
+	}
+}
+
+// The LEDs of the grabbed device, lit by the system or while a layer showing them is active
+#[derive(Default)]
+struct Leds {
+	// The LEDs the system turned on through the output device
+	system: HashSet<i32>,
+	// The LEDs of the active layers
+	layers: HashSet<i32>,
+}
+
+impl Leds {
+	// Returns the state to write to the grabbed device, the LEDs of the active layers stay lit
+	fn set_system(&mut self, led: i32, lit: bool) -> Option<(i32, bool)> {
+		if lit {
+			self.system.insert(led);
+		} else {
+			self.system.remove(&led);
+		}
+		(!self.layers.contains(&led)).then_some((led, lit))
+	}
+
+	// Returns the states to write to the grabbed device, the LEDs the system turned on stay lit
+	fn set_layers(&mut self, layers: HashSet<i32>) -> Vec<(i32, bool)> {
+		let lit = layers.difference(&self.layers).map(|x| (*x, true));
+		let unlit = self.layers.difference(&layers).map(|x| (*x, false));
+		let result = lit
+			.chain(unlit)
+			.filter(|(led, _)| !self.system.contains(led))
+			.collect();
+		self.layers = layers;
+		result
+	}
+}
+
 struct KeyboardMapper {
 	file: File,
 	device: Device,
+	// Reads the events the system writes to the output device
+	device_fd: RawFd,
 	raw_buffer: util::KeyBuffer,
 	kbct: Kbct,
 	raw_fd: RawFd,
+	timer: Timer,
+	// Mapped events that are not written yet, such as the delayed keys of macros
+	output_queue: VecDeque<KbctEvent>,
+	debounce: Option<Debounce>,
+	mouse_keys: MouseKeys,
+	leds: Leds,
+}
+
+impl KeyboardMapper {
+	// The index of the mouse keys timer in `get_timers`, the first one is armed for the mapping engine
+	const MOUSE_KEYS_TIMER: TimerId = 1;
+
+	fn write_events(&mut self, events: &[KbctEvent]) -> Result<bool> {
+		self.output_queue.extend(events);
+		self.flush_output_queue()
+	}
+
+	// Writes the queued events that are due, keeping their order
+	fn flush_output_queue(&mut self) -> Result<bool> {
+		let now = util::monotonic_now();
+		let mut written = false;
+		while let Some(x) = self.output_queue.front() {
+			if x.time > now {
+				break;
+			}
+			let ev = *x;
+			match ev.kind {
+				KbctEventKind::Key => {
+					let value = util::map_status_from_kbct(ev.ev_type);
+					self.device.write(EV_KEY, ev.code, value)?;
+				}
+				KbctEventKind::Mouse => self.write_mouse_key(ev, now)?,
+				KbctEventKind::Relative(value) => {
+					let axis = RelativeAxis::from_code(ev.code).unwrap();
+					self.device
+						.write(EV_REL, util::map_axis_from_kbct(axis), value)?;
+				}
+			}
+			self.output_queue.pop_front();
+			written = true;
+		}
+		Ok(written)
+	}
+
+	fn write_mouse_key(&mut self, ev: KbctEvent, now: Timestamp) -> Result<()> {
+		let motion = MouseMotion::from_code(ev.code).unwrap();
+		match ev.ev_type {
+			KbctKeyStatus::Clicked => self.mouse_keys.press(motion, now)?,
+			KbctKeyStatus::Released | KbctKeyStatus::ForceReleased => {
+				self.mouse_keys.release(motion)?
+			}
+			KbctKeyStatus::Pressed => {}
+		}
+		self.write_mouse_motion(now)?;
+		Ok(())
+	}
+
+	fn write_mouse_motion(&mut self, now: Timestamp) -> Result<bool> {
+		let events = self.mouse_keys.tick(now);
+		for (axis, value) in events.iter() {
+			self.device.write(EV_REL, *axis, *value)?;
+		}
+		Ok(!events.is_empty())
+	}
+
+	fn write_leds(&mut self, leds: &[(i32, bool)]) -> Result<()> {
+		for (led, lit) in leds {
+			util::write_input_event(&self.file, EV_LED, *led, *lit as i32)?;
+		}
+		if !leds.is_empty() {
+			util::write_input_event(&self.file, EV_SYN, SYN_REPORT, 0)?;
+		}
+		Ok(())
+	}
+
+	fn update_layer_leds(&mut self) -> Result<()> {
+		let layers = self
+			.kbct
+			.active_leds()
+			.into_iter()
+			.map(util::map_led_from_kbct)
+			.collect();
+		let leds = self.leds.set_layers(layers);
+		self.write_leds(&leds)
+	}
+
+	// Arms the timer for the next deadline of the mapping engine or the next queued event
+	fn update_timer(&mut self) -> Result<()> {
+		let deadline = self
+			.kbct
+			.next_deadline()
+			.into_iter()
+			.chain(self.output_queue.front().map(|x| x.time))
+			.min();
+		match deadline {
+			Some(deadline) => self.timer.schedule_at(deadline),
+			None => self.timer.cancel(),
+		}
+	}
 }
 
 impl EventObserver for KeyboardMapper {
@@ -74,39 +344,90 @@ impl EventObserver for KeyboardMapper {
 		if let Ok(uinput_events) = util::read_key_events(&mut self.file, &mut self.raw_buffer) {
 			for ev in uinput_events {
 				if let Some(kbct_ev) = util::kbct_from_uinput_event(&ev) {
+					if self
+						.debounce
+						.as_mut()
+						.is_some_and(|x| x.is_dropped(&kbct_ev))
+					{
+						debug!("debounced {}", util::KeyMapEvent::format_output(&[kbct_ev]));
+						continue;
+					}
 					let result = self.kbct.map_event(kbct_ev);
 					debug!("{}", util::KeyMapEvent::from_kbct_event(kbct_ev, &result));
-					for x in result {
-						let value = util::map_status_from_kbct(x.ev_type);
-						self.device.write(EV_KEY, x.code, value)?;
-					}
+					self.write_events(&result)?;
+				} else if ev.kind as i32 == EV_LED {
+					// The LED events of the grabbed device echo the states written to it
 				} else {
 					self.device
 						.write(ev.kind as i32, ev.code as i32, ev.value)?;
 				}
 			}
+			self.update_layer_leds()?;
+			self.update_timer()?;
 			Ok(ObserverResult::Nothing)
 		} else {
 			Ok(ObserverResult::Unsubcribe)
 		}
 	}
 
-	fn get_source_fd(&self) -> SourceFd {
+	fn get_source_fd(&self) -> SourceFd<'_> {
 		SourceFd(&self.raw_fd)
 	}
+
+	fn get_timers(&self) -> Vec<&Timer> {
+		vec![&self.timer, &self.mouse_keys.timer]
+	}
+
+	fn get_extra_sources(&self) -> Vec<SourceFd<'_>> {
+		vec![SourceFd(&self.device_fd)]
+	}
+
+	fn on_extra_event(&mut self, _: SourceId) -> Result<ObserverResult> {
+		let fd = self.device_fd;
+		while let Ok(events) = util::read_uinput_device_events(fd, &mut self.raw_buffer) {
+			for ev in events.iter().filter(|x| x.kind as i32 == EV_LED) {
+				if let Some(led) = self.leds.set_system(ev.code as i32, ev.value != 0) {
+					self.write_leds(&[led])?;
+				}
+			}
+		}
+		Ok(ObserverResult::Nothing)
+	}
+
+	fn on_timer(&mut self, timer: TimerId) -> Result<ObserverResult> {
+		if timer == KeyboardMapper::MOUSE_KEYS_TIMER {
+			if self.write_mouse_motion(util::monotonic_now())? {
+				self.device.synchronize()?;
+			}
+			return Ok(ObserverResult::Nothing);
+		}
+		let result = self.kbct.on_timeout(util::monotonic_now());
+		if !result.is_empty() {
+			debug!("timeout -> {}", util::KeyMapEvent::format_output(&result));
+		}
+		// Unlike input events, timeouts are not followed by a SYN_REPORT from the source
+		if self.write_events(&result)? {
+			self.device.synchronize()?;
+		}
+		self.update_layer_leds()?;
+		self.update_timer()?;
+		Ok(ObserverResult::Nothing)
+	}
 }
 
 struct DeviceManager {
 	inotify: Inotify,
 	conf: KbctRootConf,
+	// The typing time shared by the keyboards of each configuration
+	typing_times: Vec<KbctTypingTime>,
 	captured_kb_paths: HashSet<String>,
 	raw_fd: RawFd,
+	timer: Timer,
 }
 
 impl DeviceManager {
-	pub const SYNTHETIC_EV_FILE: &'static str = "__kbct_synthetic_event";
-
 	fn new(conf: KbctRootConf) -> Result<Box<DeviceManager>> {
+		DeviceManager::check_output_names(&conf)?;
 		let mut inotify =
 			inotify::Inotify::init().expect("Error while initializing inotify instance");
 		let raw_fd = inotify.as_raw_fd();
@@ -119,21 +440,49 @@ impl DeviceManager {
 			)
 			.expect("Failed to add file watch on /dev/input/*");
 
+		// Capture the devices that are already connected once the event loop is running
+		let timer = Timer::new()?;
+		timer.schedule_once(time::Duration::from_millis(100))?;
+
 		Ok(Box::new(DeviceManager {
 			inotify,
+			typing_times: conf.iter().map(|_| Default::default()).collect(),
 			conf,
 			raw_fd,
 			captured_kb_paths,
+			timer,
 		}))
 	}
 
-	fn force_try_capture_device() {
-		thread::spawn(move || {
-			thread::sleep(time::Duration::from_millis(100));
-			let path = format!("/dev/input/{}", DeviceManager::SYNTHETIC_EV_FILE);
-			File::create(&path).unwrap();
-			fs::remove_file(&path).unwrap();
-		});
+	// Every keyboard needs an output device of its own, which must not be captured again
+	fn check_output_names(conf: &KbctRootConf) -> Result<()> {
+		let keyboards: HashSet<&String> = conf.iter().flat_map(|x| x.keyboards()).collect();
+		let mut output_names = HashSet::new();
+		for x in conf.iter() {
+			let output = x.output();
+			for keyboard in x.keyboards() {
+				let name = output.name(keyboard);
+				if keyboards.contains(&name) {
+					return Err(KbctError::Error(format!(
+						"The output device of {:?} is captured as a keyboard: {:?}",
+						keyboard, name
+					)));
+				}
+				if !output_names.insert(name.clone()) {
+					return Err(KbctError::Error(format!(
+						"The output device of {:?} has the name of another one: {:?}",
+						keyboard, name
+					)));
+				}
+			}
+		}
+		Ok(())
+	}
+
+	fn capture_new_kbs(&mut self) -> Result<ObserverResult> {
+		DeviceManager::update_captured_kbs(self)
+			.map(ObserverResult::SubscribeNew)
+			.or(Ok(ObserverResult::Nothing))
 	}
 
 	fn update_captured_kbs(&mut self) -> Result<Vec<Box<dyn EventObserver>>> {
@@ -153,23 +502,39 @@ impl DeviceManager {
 
 		let mut ans: Vec<Box<dyn EventObserver>> = vec![];
 
-		for conf in self.conf.iter() {
+		for (conf, typing_time) in self.conf.iter().zip(self.typing_times.iter()) {
 			for kb_name in conf.keyboards() {
 				if let Some(kb_path) = available_kb_names.get(kb_name) {
 					if !self.captured_kb_paths.contains(kb_path) {
-						let kb_new_name = format!("{}-{}", "Kbct", kb_name);
+						let output = conf.output();
+						let kb_new_name = output.name(kb_name);
 						let file = util::open_readable_uinput_device(kb_path, true)?;
 						let raw_fd = file.as_raw_fd();
-						let device = util::create_writable_uinput_device(&kb_new_name)?;
+						let mut kbct =
+							Kbct::new(conf.clone(), |name| util::linux_keyname_mapper(name))?;
+						kbct.share_typing_time(typing_time.clone());
+						let output_codes: Vec<_> = kbct.output_codes().into_iter().collect();
+						let (device, device_fd) = util::create_writable_uinput_device(
+							&kb_new_name,
+							Some(&file),
+							&output_codes,
+							&output,
+						)?;
 						let raw_buffer: util::KeyBuffer = [0; util::BUF_SIZE];
-						let kbct = Kbct::new(conf.clone(), util::linux_keyname_mapper)?;
+						let timer = Timer::new()?;
 
 						let mapper = Box::new(KeyboardMapper {
 							file,
 							device,
+							device_fd,
 							raw_buffer,
 							kbct,
 							raw_fd,
+							timer,
+							output_queue: VecDeque::new(),
+							debounce: conf.debounce().map(Debounce::new),
+							mouse_keys: MouseKeys::new(conf.mouse_keys())?,
+							leds: Leds::default(),
 						});
 
 						ans.push(mapper);
@@ -191,7 +556,7 @@ impl EventObserver for DeviceManager {
 	fn on_event(&mut self, _: &Event) -> Result<ObserverResult> {
 		use inotify::EventMask;
 		let mut buffer = [0; 1024];
-		let regex: Regex = Regex::new("^(event\\d+)|(__kbct_synthetic_event)$")?;
+		let regex: Regex = Regex::new("^event\\d+$")?;
 		let events = self
 			.inotify
 			.read_events_blocking(&mut buffer)
@@ -208,17 +573,23 @@ impl EventObserver for DeviceManager {
 			.is_some();
 
 		if has_updates {
-			DeviceManager::update_captured_kbs(self)
-				.map(|observer| ObserverResult::SubscribeNew(observer))
-				.or(Ok(ObserverResult::Nothing))
+			self.capture_new_kbs()
 		} else {
 			Ok(ObserverResult::Nothing)
 		}
 	}
 
-	fn get_source_fd(&self) -> SourceFd {
+	fn get_source_fd(&self) -> SourceFd<'_> {
 		SourceFd(&self.raw_fd)
 	}
+
+	fn get_timers(&self) -> Vec<&Timer> {
+		vec![&self.timer]
+	}
+
+	fn on_timer(&mut self, _: TimerId) -> Result<ObserverResult> {
+		self.capture_new_kbs()
+	}
 }
 
 struct KeyLogger {
@@ -230,7 +601,7 @@ struct KeyLogger {
 impl KeyLogger {
 	fn new(device: String) -> Result<Box<KeyLogger>> {
 		let device_file = util::open_readable_uinput_device(&device, false)
-			.expect(format!("Could not open readable device {}", device).as_str());
+			.unwrap_or_else(|_| panic!("Could not open readable device {}", device));
 		let raw_fd = device_file.as_raw_fd();
 		Ok(Box::new(KeyLogger {
 			device_file,
@@ -243,18 +614,21 @@ impl KeyLogger {
 impl EventObserver for KeyLogger {
 	fn on_event(&mut self, _: &Event) -> Result<ObserverResult> {
 		if let Ok(events) = util::read_key_events(&mut self.device_file, &mut self.raw_buffer) {
-			for ev in events {
-				if let Some(kbct_event) = util::kbct_from_uinput_event(&ev) {
-					println!(
-						"{}",
-						format!(
-							"{} {:?}",
-							util::keycodes::code_to_name(kbct_event.code),
-							kbct_event.ev_type
-						)
-							.to_lowercase()
+			// Only keys are logged, the relative axes of mice have no key names
+			let kbct_events = events
+				.iter()
+				.filter_map(util::kbct_from_uinput_event)
+				.filter(|x| x.kind == KbctEventKind::Key);
+			for kbct_event in kbct_events {
+				println!(
+					"{}",
+					format!(
+						"{} {:?}",
+						util::keycodes::code_to_name(kbct_event.code),
+						kbct_event.ev_type
 					)
-				}
+						.to_lowercase()
+				)
 			}
 			Ok(ObserverResult::Nothing)
 		} else {
@@ -262,15 +636,15 @@ impl EventObserver for KeyLogger {
 		}
 	}
 
-	fn get_source_fd(&self) -> SourceFd {
+	fn get_source_fd(&self) -> SourceFd<'_> {
 		SourceFd(&self.raw_fd)
 	}
 }
 
 fn start_mapper_from_file_conf(config_file: String) -> Result<()> {
 	let config = serde_yaml::from_str(
-		&*std::fs::read_to_string(config_file.as_str())
-			.expect(&format!("Could not open file {}", config_file)))
+		&std::fs::read_to_string(config_file.as_str())
+			.unwrap_or_else(|_| panic!("Could not open file {}", config_file)))
 		.expect("Could not parse the configuration yaml file");
 	start_mapper(config)
 }
@@ -281,8 +655,6 @@ fn start_mapper(config: KbctRootConf) -> Result<()> {
 	evloop.register_observer(SignalReceiver::new()?)?;
 	evloop.register_observer(DeviceManager::new(config)?)?;
 
-	DeviceManager::force_try_capture_device();
-
 	info!("Starting kbct event loop, pid={}", process::id());
 	evloop.run()?;
 
@@ -378,3 +750,95 @@ fn main() -> Result<()> {
 
 mod nio;
 mod util;
+
+#[cfg(test)]
+mod tests {
+	use crate::*;
+
+	fn ev(code: i32, ev_type: KbctKeyStatus, time: Timestamp) -> KbctEvent {
+		KbctEvent::new(code, ev_type, time)
+	}
+
+	#[test]
+	fn test_debounce() {
+		use KbctKeyStatus::*;
+		let mut debounce = Debounce::new(10);
+		assert!(!debounce.is_dropped(&ev(1, Clicked, 0)));
+		assert!(!debounce.is_dropped(&ev(1, Released, 20)));
+		// The press that bounced is dropped until it is released
+		assert!(debounce.is_dropped(&ev(1, Clicked, 25)));
+		assert!(debounce.is_dropped(&ev(1, Pressed, 50)));
+		assert!(!debounce.is_dropped(&ev(2, Clicked, 50)));
+		assert!(debounce.is_dropped(&ev(1, Released, 60)));
+		assert!(!debounce.is_dropped(&ev(1, Clicked, 70)));
+		assert!(!debounce.is_dropped(&ev(1, Released, 100)));
+	}
+
+	#[test]
+	fn test_mouse_keys() -> Result<()> {
+		let conf: KbctMouseKeysConf =
+			serde_yaml::from_str("{ speed: 100, max_speed: 1100, acceleration: 1000 }")?;
+		let mut mouse_keys = MouseKeys::new(conf)?;
+
+		// The pointer moves right away on press, then by its speed
+		mouse_keys.press(MouseMotion::Left, 0)?;
+		assert_eq!(vec![(REL_X, -1)], mouse_keys.tick(0));
+		assert_eq!(Vec::<(i32, i32)>::new(), mouse_keys.tick(5));
+		assert_eq!(vec![(REL_X, -1)], mouse_keys.tick(10));
+		mouse_keys.press(MouseMotion::Down, 10)?;
+		assert_eq!(vec![(REL_X, -21), (REL_Y, 22)], mouse_keys.tick(110));
+		// The speed increases until the maximum speed is reached
+		assert_eq!(vec![(REL_X, -1100), (REL_Y, 1100)], mouse_keys.tick(1110));
+		mouse_keys.release(MouseMotion::Left)?;
+		mouse_keys.release(MouseMotion::Down)?;
+
+		mouse_keys.press(MouseMotion::WheelUp, 2000)?;
+		assert_eq!(vec![(REL_WHEEL, 1)], mouse_keys.tick(2000));
+		assert_eq!(vec![(REL_WHEEL, 1)], mouse_keys.tick(2100));
+		Ok(())
+	}
+	#[test]
+	fn test_leds() {
+		let mut leds = Leds::default();
+		assert_eq!(Some((LED_NUML, true)), leds.set_system(LED_NUML, true));
+		assert_eq!(
+			vec![(LED_SCROLLL, true)],
+			leds.set_layers(hashset![LED_SCROLLL])
+		);
+		// The LEDs of the active layers stay lit until the layers are inactive
+		assert_eq!(None, leds.set_system(LED_SCROLLL, true));
+		assert_eq!(None, leds.set_system(LED_SCROLLL, false));
+		assert_eq!(vec![(LED_SCROLLL, false)], leds.set_layers(hashset![]));
+		// The LEDs the system turned on stay lit
+		assert_eq!(
+			Vec::<(i32, bool)>::new(),
+			leds.set_layers(hashset![LED_NUML])
+		);
+		assert_eq!(Vec::<(i32, bool)>::new(), leds.set_layers(hashset![]));
+	}
+
+	#[test]
+	fn test_check_output_names() -> Result<()> {
+		let root_conf = |yml: &[&str]| -> Result<KbctRootConf> {
+			yml.iter().map(|x| KbctConf::parse(x.to_string())).collect()
+		};
+		let check = |yml: &[&str]| DeviceManager::check_output_names(&root_conf(yml)?);
+
+		let mapped = "keyboards: [Keyboard]\noutput: { name: Mapped }";
+		assert!(check(&["keyboards: [Keyboard, Mouse]"]).is_ok());
+		assert!(check(&[mapped, "keyboards: [Mouse]"]).is_ok());
+		// The output device would be captured again, by the same or another configuration
+		match check(&["keyboards: [Keyboard, Kbct-Keyboard]"]) {
+			Err(KbctError::Error(err)) => assert_eq!(
+				"The output device of \"Keyboard\" is captured as a keyboard: \"Kbct-Keyboard\"",
+				err
+			),
+			_ => panic!("Has to fail"),
+		}
+		assert!(check(&[mapped, "keyboards: [Mapped]"]).is_err());
+		// Several keyboards can't share a fixed name
+		assert!(check(&["keyboards: [Keyboard, Mouse]\noutput: { name: Mapped }"]).is_err());
+		assert!(check(&[mapped, "keyboards: [Mouse]\noutput: { name: Mapped }"]).is_err());
+		Ok(())
+	}
+}
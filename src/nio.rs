@@ -1,9 +1,12 @@
-use kbct::Result;
+use kbct::{Result, Timestamp};
 use mio::event::{Event, Source};
 use mio::unix::SourceFd;
 use mio::{Events, Interest, Poll, Token};
+use nix::sys::time::{TimeSpec, TimeValLike};
+use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
 use std::collections::HashMap;
-use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
 
 const EVENTS_CAPACITY: usize = 1024;
 
@@ -16,6 +19,10 @@ pub struct EventLoopRegistrar {
 	poll: Poll,
 	running: bool,
 	handlers: HashMap<Token, Box<dyn EventObserver>>,
+	// Maps the token of a timer to the token of its observer and the id of the timer
+	timers: HashMap<Token, (Token, TimerId)>,
+	// Maps the token of an extra source to the token of its observer and the id of the source
+	sources: HashMap<Token, (Token, SourceId)>,
 	last_token: usize,
 }
 
@@ -26,6 +33,81 @@ pub enum ObserverResult {
 	SubscribeNew(Vec<Box<dyn EventObserver>>),
 }
 
+// Index of the timer in the vector returned by `EventObserver::get_timers`
+pub type TimerId = usize;
+
+// Index of the source in the vector returned by `EventObserver::get_extra_sources`
+pub type SourceId = usize;
+
+// What woke up an observer
+enum Wakeup {
+	Event,
+	Timer(TimerId),
+	Source(SourceId),
+}
+
+// A timerfd based timer on the monotonic clock, the same clock input event timestamps use
+pub struct Timer {
+	timer_fd: TimerFd,
+	raw_fd: RawFd,
+}
+
+impl Timer {
+	pub fn new() -> Result<Timer> {
+		let timer_fd = TimerFd::new(
+			ClockId::CLOCK_MONOTONIC,
+			TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+		)?;
+		let raw_fd = timer_fd.as_raw_fd();
+		Ok(Timer { timer_fd, raw_fd })
+	}
+
+	// A zero expiration disarms a timerfd, so the shortest possible one is used instead
+	fn to_time_spec(duration: Duration) -> TimeSpec {
+		TimeSpec::nanoseconds((duration.as_nanos() as i64).max(1))
+	}
+
+	pub fn schedule_once(&self, delay: Duration) -> Result<()> {
+		let expiration = Expiration::OneShot(Timer::to_time_spec(delay));
+		Ok(self.timer_fd.set(expiration, TimerSetTimeFlags::empty())?)
+	}
+
+	pub fn schedule_at(&self, deadline: Timestamp) -> Result<()> {
+		let expiration = Expiration::OneShot(Timer::to_time_spec(Duration::from_millis(deadline)));
+		Ok(self
+			.timer_fd
+			.set(expiration, TimerSetTimeFlags::TFD_TIMER_ABSTIME)?)
+	}
+
+	pub fn schedule_repeating(&self, interval: Duration) -> Result<()> {
+		let expiration = Expiration::Interval(Timer::to_time_spec(interval));
+		Ok(self.timer_fd.set(expiration, TimerSetTimeFlags::empty())?)
+	}
+
+	pub fn cancel(&self) -> Result<()> {
+		Ok(self.timer_fd.unset()?)
+	}
+
+	fn get_source_fd(&self) -> SourceFd<'_> {
+		SourceFd(&self.raw_fd)
+	}
+
+	// Clears the readiness of the timer, returns the number of expirations since the last call
+	fn consume(&self) -> u64 {
+		let mut buf = [0u8; 8];
+		match nix::unistd::read(self.raw_fd, &mut buf) {
+			Ok(8) => u64::from_ne_bytes(buf),
+			_ => 0,
+		}
+	}
+}
+
+impl Drop for Timer {
+	fn drop(&mut self) {
+		let _ = nix::unistd::close(self.raw_fd);
+	}
+}
+
 impl EventLoop {
 	pub(crate) fn new() -> Result<EventLoop> {
 		Ok(EventLoop {
@@ -34,6 +116,8 @@ impl EventLoop {
 				poll: Poll::new()?,
 				running: true,
 				handlers: HashMap::new(),
+				timers: HashMap::new(),
+				sources: HashMap::new(),
 				last_token: 0,
 			},
 		})
@@ -52,14 +136,32 @@ impl EventLoop {
 				},
 			}
 			for ev in self.events.iter() {
-				let handler = self.registrar.handlers.get_mut(&ev.token()).unwrap();
-				match handler.on_event(ev)? {
+				let reg = &self.registrar;
+				let (token, wakeup) =
+					match (reg.timers.get(&ev.token()), reg.sources.get(&ev.token())) {
+						(Some((token, timer)), _) => (*token, Wakeup::Timer(*timer)),
+						(_, Some((token, source))) => (*token, Wakeup::Source(*source)),
+						_ => (ev.token(), Wakeup::Event),
+					};
+				// The observer might have unsubscribed while handling a previous event
+				let handler = match self.registrar.handlers.get_mut(&token) {
+					Some(handler) => handler,
+					None => continue,
+				};
+				let result = match wakeup {
+					Wakeup::Timer(timer) => {
+						if handler.get_timers()[timer].consume() == 0 {
+							continue;
+						}
+						handler.on_timer(timer)?
+					}
+					Wakeup::Source(source) => handler.on_extra_event(source)?,
+					Wakeup::Event => handler.on_event(ev)?,
+				};
+				match result {
 					ObserverResult::Nothing => {}
 					ObserverResult::Unsubcribe => {
-						handler
-							.get_source_fd()
-							.deregister(self.registrar.poll.registry())?;
-						self.registrar.handlers.remove(&ev.token());
+						EventLoop::do_unregister_observer(&mut self.registrar, token)?;
 					}
 					ObserverResult::Terminate { status: _status } => {
 						self.registrar.running = false;
@@ -76,7 +178,13 @@ impl EventLoop {
 	}
 
 	pub fn register_observer(&mut self, obs: Box<dyn EventObserver>) -> Result<()> {
-		Ok(EventLoop::do_register_observer(&mut self.registrar, obs)?)
+		EventLoop::do_register_observer(&mut self.registrar, obs)
+	}
+
+	fn next_token(reg: &mut EventLoopRegistrar) -> Token {
+		let token = Token(reg.last_token);
+		reg.last_token += 1;
+		token
 	}
 
 	fn do_register_observer(
@@ -84,21 +192,177 @@ impl EventLoop {
 		obs: Box<dyn EventObserver>,
 	) -> Result<()> {
 		let mut fd = obs.get_source_fd();
-		let token = Token(reg.last_token);
-		reg.last_token += 1;
+		let token = EventLoop::next_token(reg);
 		reg.poll
 			.registry()
 			.register(&mut fd, token, Interest::READABLE)?;
+		for (timer_id, timer) in obs.get_timers().into_iter().enumerate() {
+			let timer_token = EventLoop::next_token(reg);
+			reg.poll.registry().register(
+				&mut timer.get_source_fd(),
+				timer_token,
+				Interest::READABLE,
+			)?;
+			reg.timers.insert(timer_token, (token, timer_id));
+		}
+		for (source_id, mut source) in obs.get_extra_sources().into_iter().enumerate() {
+			let source_token = EventLoop::next_token(reg);
+			reg.poll
+				.registry()
+				.register(&mut source, source_token, Interest::READABLE)?;
+			reg.sources.insert(source_token, (token, source_id));
+		}
 		assert!(
-			reg.handlers.get(&token).is_none(),
+			!reg.handlers.contains_key(&token),
 			"Token handler is already set"
 		);
 		reg.handlers.insert(token, obs);
 		Ok(())
 	}
+
+	fn do_unregister_observer(reg: &mut EventLoopRegistrar, token: Token) -> Result<()> {
+		if let Some(handler) = reg.handlers.remove(&token) {
+			handler.get_source_fd().deregister(reg.poll.registry())?;
+			for timer in handler.get_timers() {
+				timer.get_source_fd().deregister(reg.poll.registry())?;
+			}
+			for mut source in handler.get_extra_sources() {
+				source.deregister(reg.poll.registry())?;
+			}
+			reg.timers.retain(|_, (owner, _)| *owner != token);
+			reg.sources.retain(|_, (owner, _)| *owner != token);
+		}
+		Ok(())
+	}
 }
 
 pub trait EventObserver {
 	fn on_event(&mut self, _: &Event) -> Result<ObserverResult>;
-	fn get_source_fd(&self) -> SourceFd;
+	fn get_source_fd(&self) -> SourceFd<'_>;
+
+	// Timers are registered together with the observer, their index is passed to `on_timer`
+	fn get_timers(&self) -> Vec<&Timer> {
+		vec![]
+	}
+
+	fn on_timer(&mut self, _: TimerId) -> Result<ObserverResult> {
+		Ok(ObserverResult::Nothing)
+	}
+
+	// Other file descriptors read by the observer, their index is passed to `on_extra_event`
+	fn get_extra_sources(&self) -> Vec<SourceFd<'_>> {
+		vec![]
+	}
+
+	fn on_extra_event(&mut self, _: SourceId) -> Result<ObserverResult> {
+		Ok(ObserverResult::Nothing)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::nio::*;
+
+	struct TimerCounter {
+		idle: Timer,
+		timer: Timer,
+		count: usize,
+	}
+
+	impl EventObserver for TimerCounter {
+		fn on_event(&mut self, _: &Event) -> Result<ObserverResult> {
+			panic!("The idle timer is never armed")
+		}
+
+		fn get_source_fd(&self) -> SourceFd<'_> {
+			self.idle.get_source_fd()
+		}
+
+		fn get_timers(&self) -> Vec<&Timer> {
+			vec![&self.timer]
+		}
+
+		fn on_timer(&mut self, timer: TimerId) -> Result<ObserverResult> {
+			assert_eq!(0, timer);
+			self.count += 1;
+			if self.count == 3 {
+				Ok(ObserverResult::Terminate { status: 0 })
+			} else {
+				Ok(ObserverResult::Nothing)
+			}
+		}
+	}
+
+	#[test]
+	fn test_timer() -> Result<()> {
+		let timer = Timer::new()?;
+		timer.schedule_once(Duration::from_millis(1))?;
+		std::thread::sleep(Duration::from_millis(5));
+		assert_eq!(1, timer.consume());
+		assert_eq!(0, timer.consume());
+
+		timer.schedule_at(crate::util::monotonic_now())?;
+		std::thread::sleep(Duration::from_millis(1));
+		assert_eq!(1, timer.consume());
+
+		timer.schedule_once(Duration::from_millis(1))?;
+		timer.cancel()?;
+		std::thread::sleep(Duration::from_millis(5));
+		assert_eq!(0, timer.consume());
+		Ok(())
+	}
+
+	#[test]
+	fn test_event_loop_timer() -> Result<()> {
+		let observer = Box::new(TimerCounter {
+			idle: Timer::new()?,
+			timer: Timer::new()?,
+			count: 0,
+		});
+		observer
+			.timer
+			.schedule_repeating(Duration::from_millis(1))?;
+
+		let mut evloop = EventLoop::new()?;
+		evloop.register_observer(observer)?;
+		evloop.run()
+	}
+	// Reads a timer as an extra source, like it would read any other file descriptor
+	struct SourceReader {
+		idle: Timer,
+		source: Timer,
+	}
+
+	impl EventObserver for SourceReader {
+		fn on_event(&mut self, _: &Event) -> Result<ObserverResult> {
+			panic!("The idle timer is never armed")
+		}
+
+		fn get_source_fd(&self) -> SourceFd<'_> {
+			self.idle.get_source_fd()
+		}
+
+		fn get_extra_sources(&self) -> Vec<SourceFd<'_>> {
+			vec![self.source.get_source_fd()]
+		}
+
+		fn on_extra_event(&mut self, source: SourceId) -> Result<ObserverResult> {
+			assert_eq!(0, source);
+			assert_eq!(1, self.source.consume());
+			Ok(ObserverResult::Terminate { status: 0 })
+		}
+	}
+
+	#[test]
+	fn test_event_loop_extra_source() -> Result<()> {
+		let observer = Box::new(SourceReader {
+			idle: Timer::new()?,
+			source: Timer::new()?,
+		});
+		observer.source.schedule_once(Duration::from_millis(1))?;
+
+		let mut evloop = EventLoop::new()?;
+		evloop.register_observer(observer)?;
+		evloop.run()
+	}
 }
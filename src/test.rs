@@ -4,8 +4,13 @@ use KbctKeyStatus::*;
 
 use crate::*;
 
+// Single characters are keys of their own, names of Linux keys are distinct keys as well
 fn key(str: &str) -> i32 {
-	str.as_bytes()[0] as i32
+	match MouseMotion::from_name(str) {
+		Some(motion) => motion.code(),
+		None if str.len() > 1 => crate::keycodes::name_to_code(str),
+		None => str.as_bytes()[0] as i32,
+	}
 }
 
 fn create_keymap_func(f: fn(&str) -> i32) -> impl Fn(&String) -> Option<i32> {
@@ -15,6 +20,10 @@ fn create_keymap_func(f: fn(&str) -> i32) -> impl Fn(&String) -> Option<i32> {
 	}
 }
 
+fn ev(code: Keycode, ev_type: KbctKeyStatus, time: Timestamp) -> KbctEvent {
+	KbctEvent::new(code, ev_type, time)
+}
+
 fn map_string(mp: HashMap<&str, &str>) -> HashMap<String, KeyPressConf> {
 	mp.iter()
 		.map(|(k, v)| (k.to_string(), KeyPressConf::Key(v.to_string())))
@@ -34,16 +43,20 @@ fn create_test_kbct() -> Result<Kbct> {
 				KbctComplexConf {
 					modifiers: vec_string(vec!["A", "B"]),
 					keymap: map_string(hashmap!["1" => "2", "2" => "1"]),
+					..Default::default()
 				},
 				KbctComplexConf {
 					modifiers: vec_string(vec!["A", "C"]),
 					keymap: map_string(hashmap!["2" => "3"]),
+					..Default::default()
 				},
 				KbctComplexConf {
 					modifiers: vec_string(vec!["A"]),
 					keymap: map_string(hashmap!["1" => "3"]),
+					..Default::default()
 				},
 			]),
+			..Default::default()
 		},
 		create_keymap_func(key),
 	)
@@ -51,6 +64,7 @@ fn create_test_kbct() -> Result<Kbct> {
 
 struct KbctTestContext {
 	kbct: Kbct,
+	time: Timestamp,
 }
 
 impl KbctTestContext {
@@ -58,15 +72,17 @@ impl KbctTestContext {
 		simple: HashMap<&str, &str>,
 		complex: HashMap<BTreeSet<&str>, HashMap<&str, &str>>,
 	) -> KbctTestContext {
-		fn name_to_codes(map: HashMap<&str, &str>) -> HashMap<i32, KeyPress> {
+		fn name_to_codes(map: HashMap<&str, &str>) -> HashMap<i32, KeyAction> {
 			map.into_iter()
 				.map(|(l, r)| {
 					(
 						key(l),
-						KeyPress {
+						KeyAction::Press(KeyPress {
 							code: key(r),
 							modifiers: Default::default(),
-						},
+							chord: vec![],
+							suppressed: Default::default(),
+						}),
 					)
 				})
 				.collect()
@@ -76,22 +92,42 @@ impl KbctTestContext {
 
 		let complex_codes = complex
 			.into_iter()
-			.map(|(l, r)| (l.into_iter().map(|x| key(x)).collect(), name_to_codes(r)))
+			.map(|(l, r)| (l.into_iter().map(key).collect(), name_to_codes(r)))
 			.collect();
 
 		let kbct = Kbct::new_test(simple_codes, complex_codes);
-		KbctTestContext { kbct }
+		KbctTestContext { kbct, time: 0 }
+	}
+
+	fn from_yaml(yml: &str) -> KbctTestContext {
+		let conf = KbctConf::parse(yml.to_string()).unwrap();
+		let kbct = Kbct::new(conf, create_keymap_func(key)).unwrap();
+		KbctTestContext { kbct, time: 0 }
 	}
 
 	fn run_test(&mut self, s: &str, ev_type: KbctKeyStatus, expected: Vec<(&str, KbctKeyStatus)>) {
 		let exp: Vec<KbctEvent> = expected
 			.iter()
-			.map(|(x, y)| Kbct::make_ev(key(x), *y))
+			.map(|(x, y)| ev(key(x), *y, self.time))
+			.collect();
+		let result = self.kbct.map_event(ev(key(s), ev_type, self.time));
+		assert_eq!(exp, result);
+	}
+
+	fn timeout(&mut self, millis: Timestamp, expected: Vec<(&str, KbctKeyStatus)>) {
+		self.time += millis;
+		let exp: Vec<KbctEvent> = expected
+			.iter()
+			.map(|(x, y)| ev(key(x), *y, self.time))
 			.collect();
-		let result = self.kbct.map_event(Kbct::make_ev(key(s), ev_type));
+		let result = self.kbct.on_timeout(self.time);
 		assert_eq!(exp, result);
 	}
 
+	fn wait(&mut self, millis: Timestamp) {
+		self.time += millis;
+	}
+
 	fn click(&mut self, key: &str, expected: Vec<(&str, KbctKeyStatus)>) {
 		self.run_test(key, Clicked, expected);
 	}
@@ -109,6 +145,7 @@ impl KbctTestContext {
 fn test_map_event() -> Result<()> {
 	let mut test = KbctTestContext {
 		kbct: create_test_kbct().unwrap(),
+		time: 0,
 	};
 
 	// Test single key with click and press
@@ -213,21 +250,21 @@ fn test_3() {
 #[test]
 fn test_active_mapping() -> Result<()> {
 	let mut kbct = create_test_kbct()?;
-	kbct.map_event(Kbct::make_ev(key("A"), Clicked));
-	kbct.map_event(Kbct::make_ev(key("B"), Clicked));
-	kbct.map_event(Kbct::make_ev(key("C"), Clicked));
-	let active = kbct.get_active_complex_modifiers().unwrap();
-	assert_eq!(btreeset![key("A"), key("C")], *active.0);
+	kbct.map_event(ev(key("A"), Clicked, 0));
+	kbct.map_event(ev(key("B"), Clicked, 0));
+	kbct.map_event(ev(key("C"), Clicked, 0));
+	let active = kbct.get_active_layers();
+	assert_eq!(btreeset![key("A"), key("C")], active[0].0);
 
 	let mut kbct = create_test_kbct()?;
-	kbct.map_event(Kbct::make_ev(key("A"), Clicked));
-	let active = kbct.get_active_complex_modifiers().unwrap();
-	assert_eq!(btreeset![key("A")], *active.0);
+	kbct.map_event(ev(key("A"), Clicked, 0));
+	let active = kbct.get_active_layers();
+	assert_eq!(btreeset![key("A")], active[0].0);
 
 	let mut kbct = create_test_kbct()?;
-	kbct.map_event(Kbct::make_ev(key("B"), Clicked));
-	let active = kbct.get_active_complex_modifiers();
-	assert!(active.is_none());
+	kbct.map_event(ev(key("B"), Clicked, 0));
+	let active = kbct.get_active_layers();
+	assert!(active.is_empty());
 	Ok(())
 }
 
@@ -241,19 +278,22 @@ fn test_create_kbct_fail() -> Result<()> {
 				KbctComplexConf {
 					modifiers: vec!["A".to_string(), "B".to_string()],
 					keymap: map_string(hashmap!["1" => "2", "2" => "1"]),
+					..Default::default()
 				},
 				KbctComplexConf {
 					modifiers: vec!["A".to_string()],
 					keymap: map_string(hashmap!["1" => "3"]),
+					..Default::default()
 				},
 			]),
+			..Default::default()
 		},
 		|_| None,
 	);
 	let err = "Configuration contains unknown keys: \
      {\"1\", \"2\", \"3\", \"A\", \"B\", \"C\", \"D\"}";
 	match kbct {
-		Ok(_) => assert!(false), // Has to fail
+		Ok(_) => panic!("Has to fail"),
 		Err(KbctError::Error(k)) => assert_eq!(err, k),
 		_ => {}
 	}
@@ -270,6 +310,7 @@ fn test_create_simple_kbct() -> Result<()> {
 			keyboards: vec![],
 			keymap: Some(simple),
 			layers: None,
+			..Default::default()
 		},
 		create_keymap_func(|x| match x {
 			"K1" => 1,
@@ -278,7 +319,10 @@ fn test_create_simple_kbct() -> Result<()> {
 		}),
 	)?;
 	assert_eq!(1, kbct.simple_map.len());
-	assert_eq!(2, kbct.simple_map.get(&1).unwrap().code);
+	match kbct.simple_map.get(&1).unwrap() {
+		KeyAction::Press(key_press) => assert_eq!(2, key_press.code),
+		action => panic!("Unexpected action {:?}", action),
+	}
 	Ok(())
 }
 
@@ -309,7 +353,7 @@ fn test_conf_parser() -> Result<()> {
 	let conf = KbctConf::parse(yml.to_string())?;
 	assert!(conf.layers.is_some());
 	let complex_vec = conf.layers.unwrap();
-	let conf = complex_vec.get(0).unwrap();
+	let conf = complex_vec.first().unwrap();
 	assert_eq!(
 		&KeyPressConf::Key("UP_ARROW".to_string()),
 		conf.keymap.get("KEY_I").unwrap()
@@ -328,3 +372,1305 @@ fn test_conf_parser() -> Result<()> {
 
 	Ok(())
 }
+
+#[test]
+fn test_tap_hold() {
+	let mut kbct = KbctTestContext::from_yaml("keyboards: []\nkeymap:\n  C: { tap: E, hold: L }");
+
+	kbct.click("C", vec![]);
+	kbct.release("C", vec![("E", Clicked), ("E", Released)]);
+
+	kbct.click("C", vec![]);
+	kbct.press("C", vec![]);
+	kbct.timeout(200, vec![("L", Clicked)]);
+	kbct.press("C", vec![("L", Pressed)]);
+	kbct.release("C", vec![("L", Released)]);
+
+	// Without hold flavors an interrupted tap is still a tap, keys are replayed in order
+	kbct.click("C", vec![]);
+	kbct.click("A", vec![]);
+	kbct.release("A", vec![]);
+	kbct.release(
+		"C",
+		vec![
+			("E", Clicked),
+			("A", Clicked),
+			("A", Released),
+			("E", Released),
+		],
+	);
+}
+
+#[test]
+fn test_tap_hold_permissive_hold() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\nkeymap:\n  C: { tap: E, hold: L, permissive_hold: true }",
+	);
+
+	kbct.click("C", vec![]);
+	kbct.click("A", vec![]);
+	kbct.release("A", vec![("L", Clicked), ("A", Clicked), ("A", Released)]);
+	kbct.release("C", vec![("L", Released)]);
+
+	// A key pressed before the tap-hold key does not count
+	kbct.click("A", vec![("A", Clicked)]);
+	kbct.click("C", vec![]);
+	kbct.release("A", vec![]);
+	kbct.release("C", vec![("E", Clicked), ("A", Released), ("E", Released)]);
+}
+
+#[test]
+fn test_tap_hold_on_other_key_press() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  C: { tap: E, hold: L, hold_on_other_key_press: true }",
+	);
+
+	kbct.click("C", vec![]);
+	kbct.click("A", vec![("L", Clicked), ("A", Clicked)]);
+	kbct.release("A", vec![("A", Released)]);
+	kbct.release("C", vec![("L", Released)]);
+}
+
+#[test]
+fn test_tap_hold_layer() {
+	// A layer held by a tap-hold key is active without pressing any key
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  C: { tap: E, hold: { layer: nav }, hold_on_other_key_press: true }\n\
+		layers:\n  - name: nav\n    keymap:\n      I: U",
+	);
+
+	kbct.click("C", vec![]);
+	kbct.click("I", vec![("U", Clicked)]);
+	kbct.release("I", vec![("U", Released)]);
+	kbct.release("C", vec![]);
+	kbct.click("I", vec![("I", Clicked)]);
+	kbct.release("I", vec![("I", Released)]);
+
+	kbct.click("C", vec![]);
+	kbct.release("C", vec![("E", Clicked), ("E", Released)]);
+}
+
+#[test]
+fn test_tap_hold_conf() {
+	let yml = "keyboards: []\nkeymap:\n  C: { tap: E, hold: { tap: A, hold: B } }";
+	let conf = KbctConf::parse(yml.to_string()).unwrap();
+	assert!(Kbct::new(conf, create_keymap_func(key)).is_err());
+
+	let yml = "keyboards: []\nkeymap:\n  C: { tap: E, hold: X }";
+	let conf = KbctConf::parse(yml.to_string()).unwrap();
+	match Kbct::new(
+		conf,
+		create_keymap_func(|x| if x == "X" { -1 } else { key(x) }),
+	) {
+		Err(KbctError::Error(err)) => {
+			assert_eq!("Configuration contains unknown keys: {\"X\"}", err)
+		}
+		_ => panic!("Has to fail"),
+	}
+}
+
+#[test]
+fn test_tap_hold_timeout() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\nkeymap:\n  C: { tap: E, hold: L, timeout: 100 }",
+	);
+
+	assert_eq!(None, kbct.kbct.next_deadline());
+	kbct.click("C", vec![]);
+	assert_eq!(Some(100), kbct.kbct.next_deadline());
+	kbct.timeout(99, vec![]);
+	kbct.release("C", vec![("E", Clicked), ("E", Released)]);
+	assert_eq!(None, kbct.kbct.next_deadline());
+
+	// Keys typed before the timeout are replayed after the hold key
+	kbct.click("C", vec![]);
+	kbct.click("A", vec![]);
+	kbct.timeout(100, vec![("L", Clicked), ("A", Clicked)]);
+	kbct.release("A", vec![("A", Released)]);
+	kbct.release("C", vec![("L", Released)]);
+
+	// A late timer is caught up by the next event
+	kbct.click("C", vec![]);
+	kbct.wait(150);
+	kbct.click("A", vec![("L", Clicked), ("A", Clicked)]);
+	kbct.release("C", vec![("L", Released)]);
+	kbct.release("A", vec![("A", Released)]);
+
+	// Buffered events are replayed with their own times
+	kbct.click("C", vec![]);
+	kbct.wait(50);
+	kbct.release("C", vec![("E", Clicked), ("E", Released)]);
+	kbct.click("C", vec![]);
+	kbct.wait(40);
+	kbct.click("A", vec![]);
+	kbct.wait(40);
+	kbct.release("C", vec![("E", Clicked), ("A", Clicked), ("E", Released)]);
+	assert_eq!(None, kbct.kbct.next_deadline());
+	kbct.release("A", vec![("A", Released)]);
+}
+
+#[test]
+fn test_one_shot_modifier() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  S: { one_shot: leftshift }\n  C: { one_shot: rightctrl, timeout: 100 }",
+	);
+
+	kbct.click("S", vec![]);
+	kbct.release("S", vec![]);
+	kbct.click("A", vec![("leftshift", Clicked), ("A", Clicked)]);
+	kbct.release("A", vec![("A", Released), ("leftshift", Released)]);
+	kbct.click("A", vec![("A", Clicked)]);
+	kbct.release("A", vec![("A", Released)]);
+
+	// Held down it behaves as a plain modifier
+	kbct.click("S", vec![]);
+	kbct.click("A", vec![("leftshift", Clicked), ("A", Clicked)]);
+	kbct.release("A", vec![("A", Released)]);
+	kbct.release("S", vec![("leftshift", Released)]);
+	kbct.click("A", vec![("A", Clicked)]);
+	kbct.release("A", vec![("A", Released)]);
+
+	// One-shot modifiers are not consumed by other modifiers
+	kbct.click("S", vec![]);
+	kbct.release("S", vec![]);
+	kbct.click("rightalt", vec![("rightalt", Clicked)]);
+	kbct.click("A", vec![("leftshift", Clicked), ("A", Clicked)]);
+	kbct.release("A", vec![("A", Released), ("leftshift", Released)]);
+	kbct.release("rightalt", vec![("rightalt", Released)]);
+
+	// One-shot modifiers stack
+	kbct.click("S", vec![]);
+	kbct.release("S", vec![]);
+	kbct.click("C", vec![]);
+	kbct.release("C", vec![]);
+	kbct.click(
+		"A",
+		vec![
+			("leftshift", Clicked),
+			("rightctrl", Clicked),
+			("A", Clicked),
+		],
+	);
+	kbct.release(
+		"A",
+		vec![
+			("A", Released),
+			("leftshift", Released),
+			("rightctrl", Released),
+		],
+	);
+
+	// A second tap cancels
+	kbct.click("S", vec![]);
+	kbct.release("S", vec![]);
+	kbct.click("S", vec![]);
+	kbct.release("S", vec![]);
+	kbct.click("A", vec![("A", Clicked)]);
+	kbct.release("A", vec![("A", Released)]);
+
+	// So does the timeout
+	kbct.click("C", vec![]);
+	kbct.release("C", vec![]);
+	assert_eq!(Some(kbct.time + 100), kbct.kbct.next_deadline());
+	kbct.timeout(100, vec![]);
+	assert_eq!(None, kbct.kbct.next_deadline());
+	kbct.click("A", vec![("A", Clicked)]);
+	kbct.release("A", vec![("A", Released)]);
+}
+
+#[test]
+fn test_one_shot_deadlines() {
+	// Each one-shot modifier expires at its own deadline
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  S: { one_shot: leftshift }\n  C: { one_shot: rightctrl, timeout: 100 }",
+	);
+
+	kbct.click("C", vec![]);
+	kbct.release("C", vec![]);
+	let deadline = kbct.time + 100;
+	kbct.click("S", vec![]);
+	kbct.release("S", vec![]);
+	assert_eq!(Some(deadline), kbct.kbct.next_deadline());
+	kbct.timeout(100, vec![]);
+	assert_eq!(None, kbct.kbct.next_deadline());
+	kbct.click("A", vec![("leftshift", Clicked), ("A", Clicked)]);
+	kbct.release("A", vec![("A", Released), ("leftshift", Released)]);
+
+	kbct.click("S", vec![]);
+	kbct.release("S", vec![]);
+	kbct.click("C", vec![]);
+	kbct.release("C", vec![]);
+	kbct.timeout(100, vec![]);
+	kbct.click("A", vec![("leftshift", Clicked), ("A", Clicked)]);
+	kbct.release("A", vec![("A", Released), ("leftshift", Released)]);
+
+	// Cancelling a modifier clears its deadline
+	kbct.click("C", vec![]);
+	kbct.release("C", vec![]);
+	kbct.click("C", vec![]);
+	kbct.release("C", vec![]);
+	assert_eq!(None, kbct.kbct.next_deadline());
+}
+
+#[test]
+fn test_one_shot_layer() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		layers:\n  - modifiers: [rightalt]\n    one_shot: true\n    keymap:\n      I: U",
+	);
+
+	kbct.click("rightalt", vec![("rightalt", Clicked)]);
+	kbct.release("rightalt", vec![("rightalt", Released)]);
+	kbct.click("I", vec![("U", Clicked)]);
+	kbct.release("I", vec![("U", Released)]);
+	kbct.click("I", vec![("I", Clicked)]);
+	kbct.release("I", vec![("I", Released)]);
+
+	// Used as a regular layer it is not armed
+	kbct.click("rightalt", vec![("rightalt", Clicked)]);
+	kbct.click("I", vec![("rightalt", ForceReleased), ("U", Clicked)]);
+	kbct.release("I", vec![("U", Released)]);
+	kbct.release("rightalt", vec![]);
+	kbct.click("I", vec![("I", Clicked)]);
+	kbct.release("I", vec![("I", Released)]);
+
+	// A second tap cancels
+	for _ in 0..2 {
+		kbct.click("rightalt", vec![("rightalt", Clicked)]);
+		kbct.release("rightalt", vec![("rightalt", Released)]);
+	}
+	kbct.click("I", vec![("I", Clicked)]);
+	kbct.release("I", vec![("I", Released)]);
+}
+
+#[test]
+fn test_layer_actions() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  M: { layer: nav }\n  \
+		  T: { layer: nav, mode: toggle }\n  \
+		  O: { layer: sym, mode: to }\n\
+		layers:\n  \
+		  - name: nav\n    keymap:\n      I: U\n  \
+		  - name: sym\n    keymap:\n      I: S\n      O: { layer: sym, mode: toggle }\n  \
+		  - modifiers: [rightalt]\n    keymap:\n      I: D",
+	);
+
+	// Momentary
+	kbct.click("M", vec![]);
+	kbct.press("M", vec![]);
+	kbct.click("I", vec![("U", Clicked)]);
+	kbct.release("I", vec![("U", Released)]);
+	kbct.release("M", vec![]);
+	kbct.click("I", vec![("I", Clicked)]);
+	kbct.release("I", vec![("I", Released)]);
+
+	// Toggle
+	kbct.click("T", vec![]);
+	kbct.release("T", vec![]);
+	kbct.click("I", vec![("U", Clicked)]);
+	kbct.release("I", vec![("U", Released)]);
+
+	// The layer activated last wins
+	kbct.click("rightalt", vec![("rightalt", Clicked)]);
+	kbct.click("I", vec![("rightalt", ForceReleased), ("D", Clicked)]);
+	kbct.release("I", vec![("D", Released)]);
+	kbct.release("rightalt", vec![]);
+	kbct.click("I", vec![("U", Clicked)]);
+	kbct.release("I", vec![("U", Released)]);
+
+	// To deactivates the other layers
+	kbct.click("O", vec![]);
+	kbct.release("O", vec![]);
+	kbct.click("I", vec![("S", Clicked)]);
+	kbct.release("I", vec![("S", Released)]);
+	kbct.click("O", vec![]);
+	kbct.release("O", vec![]);
+	kbct.click("I", vec![("I", Clicked)]);
+	kbct.release("I", vec![("I", Released)]);
+}
+
+#[test]
+fn test_layer_actions_conf() {
+	let err = |yml: &str| match Kbct::new(
+		KbctConf::parse(yml.to_string()).unwrap(),
+		create_keymap_func(key),
+	) {
+		Err(KbctError::Error(err)) => err,
+		_ => panic!("Has to fail"),
+	};
+
+	assert_eq!(
+		"Unknown layer: \"sym\"",
+		err("keyboards: []\nkeymap:\n  T: { layer: sym }")
+	);
+	assert_eq!(
+		"Duplicate layer: \"nav\"",
+		err("keyboards: []\nlayers:\n  - name: nav\n    keymap: {}\n  - name: nav\n    keymap: {}")
+	);
+	assert_eq!(
+		"Layers without modifiers have to be named",
+		err("keyboards: []\nlayers:\n  - keymap:\n      I: U")
+	);
+}
+
+#[test]
+fn test_layer_priority() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  T: { layer: nav, mode: toggle }\n  \
+		  J: A\n\
+		layers:\n  \
+		  - name: nav\n    priority: 1\n    keymap:\n      I: U\n      J: transparent\n      K: blocked\n  \
+		  - modifiers: [rightalt]\n    keymap:\n      I: D\n      J: E\n      K: F\n      H: G",
+	);
+
+	kbct.click("T", vec![]);
+	kbct.release("T", vec![]);
+
+	// Keys fall through to the next active layer
+	kbct.click("rightalt", vec![("rightalt", Clicked)]);
+	kbct.click("I", vec![("rightalt", ForceReleased), ("U", Clicked)]);
+	kbct.release("I", vec![("U", Released)]);
+	kbct.click("J", vec![("E", Clicked)]);
+	kbct.release("J", vec![("E", Released)]);
+	kbct.click("H", vec![("G", Clicked)]);
+	kbct.release("H", vec![("G", Released)]);
+	kbct.click("K", vec![]);
+	kbct.press("K", vec![]);
+	kbct.release("K", vec![]);
+	kbct.release("rightalt", vec![]);
+
+	// And then to the base keymap
+	kbct.click("J", vec![("A", Clicked)]);
+	kbct.release("J", vec![("A", Released)]);
+	kbct.click("H", vec![("H", Clicked)]);
+	kbct.release("H", vec![("H", Released)]);
+	kbct.click("K", vec![]);
+	kbct.release("K", vec![]);
+
+	kbct.click("T", vec![]);
+	kbct.release("T", vec![]);
+	kbct.click("K", vec![("K", Clicked)]);
+	kbct.release("K", vec![("K", Released)]);
+}
+
+#[test]
+fn test_combos() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		combos:\n  \
+		  - keys: [J, K]\n    target: E\n  \
+		  - keys: [S, D, F]\n    target: N\n    timeout: 80\n  \
+		  - keys: [S, D]\n    target: X",
+	);
+
+	kbct.click("J", vec![]);
+	kbct.click("K", vec![("E", Clicked)]);
+	kbct.press("J", vec![("E", Pressed)]);
+	kbct.press("K", vec![]);
+	kbct.release("K", vec![("E", Released)]);
+	kbct.release("J", vec![]);
+
+	// Keys still type on their own
+	kbct.click("J", vec![]);
+	kbct.timeout(50, vec![("J", Clicked)]);
+	kbct.click("K", vec![]);
+	kbct.release("K", vec![("K", Clicked), ("K", Released)]);
+	kbct.release("J", vec![("J", Released)]);
+
+	// Other keys break the combo
+	kbct.click("J", vec![]);
+	kbct.click("A", vec![("J", Clicked), ("A", Clicked)]);
+	kbct.release("J", vec![("J", Released)]);
+	kbct.release("A", vec![("A", Released)]);
+
+	// Longer combos are waited for
+	kbct.click("S", vec![]);
+	kbct.click("D", vec![]);
+	kbct.click("F", vec![("N", Clicked)]);
+	kbct.release("D", vec![("N", Released)]);
+	kbct.release("S", vec![]);
+	kbct.release("F", vec![]);
+
+	kbct.click("D", vec![]);
+	kbct.click("S", vec![]);
+	assert_eq!(Some(kbct.time + 80), kbct.kbct.next_deadline());
+	kbct.timeout(80, vec![("X", Clicked)]);
+	kbct.release("S", vec![("X", Released)]);
+	kbct.release("D", vec![]);
+	assert_eq!(None, kbct.kbct.next_deadline());
+}
+
+#[test]
+fn test_combos_conf() {
+	let yml = "keyboards: []\ncombos:\n  - keys: [J]\n    target: E";
+	let conf = KbctConf::parse(yml.to_string()).unwrap();
+	assert!(Kbct::new(conf, create_keymap_func(key)).is_err());
+
+	let yml = "keyboards: []\ncombos:\n  - keys: [J, K]\n    target: { tap: E, hold: F }";
+	let conf = KbctConf::parse(yml.to_string()).unwrap();
+	assert!(Kbct::new(conf, create_keymap_func(key)).is_err());
+}
+
+#[test]
+fn test_macro() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  M:\n    macro: [{ modifiers: [leftctrl], key: A }, { text: 'Hi!' }]\n    delay: 10",
+	);
+
+	let step = |time: Timestamp, keys: Vec<(&str, KbctKeyStatus)>| -> Vec<KbctEvent> {
+		keys.iter().map(|(k, s)| ev(key(k), *s, time)).collect()
+	};
+	let expected: Vec<KbctEvent> = [
+		step(5, vec![("leftctrl", Clicked), ("A", Clicked)]),
+		step(5, vec![("A", Released), ("leftctrl", Released)]),
+		step(15, vec![("leftshift", Clicked), ("h", Clicked)]),
+		step(15, vec![("h", Released), ("leftshift", Released)]),
+		step(25, vec![("i", Clicked), ("i", Released)]),
+		step(35, vec![("leftshift", Clicked), ("1", Clicked)]),
+		step(35, vec![("1", Released), ("leftshift", Released)]),
+	]
+	.concat();
+
+	kbct.wait(5);
+	assert_eq!(expected, kbct.kbct.map_event(ev(key("M"), Clicked, 5)));
+	kbct.press("M", vec![]);
+	kbct.release("M", vec![]);
+	kbct.click("A", vec![("A", Clicked)]);
+	kbct.release("A", vec![("A", Released)]);
+
+	let yml = "keyboards: []\nkeymap:\n  M: { macro: [{ text: 'é' }] }";
+	let conf = KbctConf::parse(yml.to_string()).unwrap();
+	match Kbct::new(conf, create_keymap_func(key)) {
+		Err(KbctError::Error(err)) => assert_eq!("Can not type 'é'", err),
+		_ => panic!("Has to fail"),
+	}
+}
+
+#[test]
+fn test_unicode() {
+	let step = |time: Timestamp, keys: Vec<(&str, KbctKeyStatus)>| -> Vec<KbctEvent> {
+		keys.iter().map(|(k, s)| ev(key(k), *s, time)).collect()
+	};
+	let tap = |time: Timestamp, k: &str| step(time, vec![(k, Clicked), (k, Released)]);
+
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  E: { unicode: 'é' }",
+	);
+	let expected: Vec<KbctEvent> = [
+		step(
+			0,
+			vec![
+				("leftctrl", Clicked),
+				("leftshift", Clicked),
+				("u", Clicked),
+			],
+		),
+		step(
+			0,
+			vec![
+				("u", Released),
+				("leftshift", Released),
+				("leftctrl", Released),
+			],
+		),
+		tap(0, "e"),
+		tap(0, "9"),
+		tap(0, "space"),
+	]
+	.concat();
+	assert_eq!(expected, kbct.kbct.map_event(ev(key("E"), Clicked, 0)));
+	kbct.release("E", vec![]);
+
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		unicode_input:\n  \
+		  prefix: [{ modifiers: [leftctrl], key: X }, '8']\n  \
+		  suffix: [{ text: ' ' }]\n  \
+		  delay: 5\n\
+		keymap:\n  \
+		  L: { unicode: 'λ' }",
+	);
+	let expected: Vec<KbctEvent> = [
+		step(0, vec![("leftctrl", Clicked), ("X", Clicked)]),
+		step(0, vec![("X", Released), ("leftctrl", Released)]),
+		tap(5, "8"),
+		tap(10, "3"),
+		tap(15, "b"),
+		tap(20, "b"),
+		tap(25, "space"),
+	]
+	.concat();
+	assert_eq!(expected, kbct.kbct.map_event(ev(key("L"), Clicked, 0)));
+	kbct.release("L", vec![]);
+}
+
+#[test]
+fn test_tap_dance() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  S:\n    tap_dance: [S, { modifiers: [leftshift], key: S }, { tap: T, hold: H }]\n    timeout: 100\n  \
+		  D: { tap_dance: [E] }",
+	);
+	let shifted = vec![
+		("leftshift", Clicked),
+		("S", Clicked),
+		("S", Released),
+		("leftshift", Released),
+	];
+
+	kbct.click("S", vec![]);
+	kbct.release("S", vec![]);
+	kbct.timeout(100, vec![("S", Clicked), ("S", Released)]);
+
+	kbct.click("S", vec![]);
+	kbct.release("S", vec![]);
+	kbct.wait(50);
+	kbct.click("S", vec![]);
+	kbct.release("S", vec![]);
+	kbct.timeout(100, shifted.clone());
+
+	// The last tap-hold action is held past the timeout
+	for _ in 0..2 {
+		kbct.click("S", vec![]);
+		kbct.release("S", vec![]);
+	}
+	kbct.click("S", vec![]);
+	kbct.timeout(100, vec![("H", Clicked)]);
+	kbct.press("S", vec![("H", Pressed)]);
+	kbct.release("S", vec![("H", Released)]);
+
+	// No more taps can follow the last action
+	for _ in 0..2 {
+		kbct.click("S", vec![]);
+		kbct.release("S", vec![]);
+	}
+	kbct.click("S", vec![]);
+	kbct.release("S", vec![("T", Clicked), ("T", Released)]);
+	assert_eq!(None, kbct.kbct.next_deadline());
+
+	// Another key ends the dance
+	kbct.click("S", vec![]);
+	kbct.release("S", vec![]);
+	kbct.click("S", vec![]);
+	kbct.release("S", vec![]);
+	let mut expected = shifted;
+	expected.push(("A", Clicked));
+	kbct.click("A", expected);
+	kbct.release("A", vec![("A", Released)]);
+
+	// A held key resolves when the timeout passes
+	kbct.click("S", vec![]);
+	kbct.timeout(100, vec![("S", Clicked)]);
+	kbct.press("S", vec![("S", Pressed)]);
+	kbct.release("S", vec![("S", Released)]);
+
+	// A single action does not wait for more taps
+	kbct.click("D", vec![("E", Clicked)]);
+	kbct.release("D", vec![("E", Released)]);
+
+	let yml = "keyboards: []\nkeymap:\n  D: { tap_dance: [] }";
+	let conf = KbctConf::parse(yml.to_string()).unwrap();
+	match Kbct::new(conf, create_keymap_func(key)) {
+		Err(KbctError::Error(err)) => assert_eq!("Tap dances need at least one action", err),
+		_ => panic!("Has to fail"),
+	}
+}
+
+#[test]
+fn test_leader() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  Q: leader\n\
+		leader:\n  \
+		  timeout: 500\n  \
+		  sequences:\n    \
+		    - { keys: [G, S], target: X }\n    \
+		    - { keys: [G], target: Y }\n    \
+		    - { keys: [D, D], target: E }",
+	);
+
+	kbct.click("Q", vec![]);
+	kbct.release("Q", vec![]);
+	kbct.click("G", vec![]);
+	kbct.release("G", vec![]);
+	kbct.click("S", vec![("X", Clicked)]);
+	kbct.release("S", vec![("X", Released)]);
+
+	// A sequence that is a prefix of another one fires on timeout
+	kbct.click("Q", vec![]);
+	kbct.release("Q", vec![]);
+	kbct.click("G", vec![]);
+	kbct.release("G", vec![]);
+	kbct.timeout(500, vec![("Y", Clicked), ("Y", Released)]);
+
+	// Swallowed keys are replayed if they do not match
+	kbct.click("Q", vec![]);
+	kbct.release("Q", vec![]);
+	kbct.click("G", vec![]);
+	kbct.release("G", vec![]);
+	kbct.click("A", vec![("G", Clicked), ("G", Released), ("A", Clicked)]);
+	kbct.release("A", vec![("A", Released)]);
+
+	// Only the last key stands for the sequence, the other ones are dropped
+	kbct.click("Q", vec![]);
+	kbct.click("D", vec![]);
+	kbct.release("D", vec![]);
+	kbct.click("D", vec![("E", Clicked)]);
+	kbct.release("Q", vec![]);
+	kbct.release("D", vec![("E", Released)]);
+
+	// Keys held before the leader key are passed on
+	kbct.click("A", vec![("A", Clicked)]);
+	kbct.click("Q", vec![]);
+	kbct.release("A", vec![("A", Released)]);
+	kbct.release("Q", vec![]);
+	kbct.timeout(500, vec![]);
+	assert_eq!(None, kbct.kbct.next_deadline());
+
+	let yml = "keyboards: []\nleader: { sequences: [{ keys: [G], target: X }, { keys: [G], target: Y }] }";
+	let conf = KbctConf::parse(yml.to_string()).unwrap();
+	match Kbct::new(conf, create_keymap_func(key)) {
+		Err(KbctError::Error(err)) => assert_eq!("Duplicate leader sequence: [\"G\"]", err),
+		_ => panic!("Has to fail"),
+	}
+}
+
+#[test]
+fn test_caps_word() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  C: caps_word\n\
+		caps_word: { timeout: 1000 }",
+	);
+	let shifted = |k| vec![("leftshift", Clicked), (k, Clicked)];
+	let shifted_release = |k| vec![(k, Released), ("leftshift", Released)];
+
+	kbct.click("C", vec![]);
+	kbct.release("C", vec![]);
+	kbct.click("h", shifted("h"));
+	kbct.release("h", shifted_release("h"));
+	kbct.click("1", vec![("1", Clicked)]);
+	kbct.release("1", vec![("1", Released)]);
+	kbct.click("i", shifted("i"));
+	kbct.release("i", shifted_release("i"));
+	// Keys that do not continue the word end it
+	kbct.click(".", vec![(".", Clicked)]);
+	kbct.release(".", vec![(".", Released)]);
+	kbct.click("h", vec![("h", Clicked)]);
+	kbct.release("h", vec![("h", Released)]);
+
+	kbct.click("C", vec![]);
+	kbct.release("C", vec![]);
+	kbct.click("h", shifted("h"));
+	kbct.release("h", shifted_release("h"));
+	kbct.timeout(1000, vec![]);
+	kbct.click("h", vec![("h", Clicked)]);
+	kbct.release("h", vec![("h", Released)]);
+	assert_eq!(None, kbct.kbct.next_deadline());
+
+	// Caps word is turned off by its key
+	kbct.click("C", vec![]);
+	kbct.release("C", vec![]);
+	kbct.click("C", vec![]);
+	kbct.release("C", vec![]);
+	kbct.click("h", vec![("h", Clicked)]);
+	kbct.release("h", vec![("h", Released)]);
+
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  C: caps_word\n\
+		caps_word:\n  \
+		  keys: { '.': { modifiers: [leftshift], key: '-' } }",
+	);
+	kbct.click("C", vec![]);
+	kbct.release("C", vec![]);
+	kbct.click(".", shifted("-"));
+	kbct.release(".", shifted_release("-"));
+	kbct.click("1", vec![("1", Clicked)]);
+	kbct.release("1", vec![("1", Released)]);
+	kbct.click("h", vec![("h", Clicked)]);
+	kbct.release("h", vec![("h", Released)]);
+}
+
+#[test]
+fn test_auto_shift() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  A: E\n\
+		auto_shift: { keys: [A, '1'], timeout: 100 }",
+	);
+
+	kbct.click("A", vec![]);
+	kbct.release("A", vec![("E", Clicked), ("E", Released)]);
+
+	kbct.click("A", vec![]);
+	kbct.press("A", vec![]);
+	kbct.timeout(
+		100,
+		vec![
+			("leftshift", Clicked),
+			("E", Clicked),
+			("E", Released),
+			("leftshift", Released),
+		],
+	);
+	kbct.press("A", vec![]);
+	kbct.release("A", vec![]);
+
+	// Another key resolves the key as not shifted
+	kbct.click("1", vec![]);
+	kbct.click("B", vec![("1", Clicked), ("B", Clicked)]);
+	kbct.release("1", vec![("1", Released)]);
+	kbct.release("B", vec![("B", Released)]);
+	assert_eq!(None, kbct.kbct.next_deadline());
+
+	// Shortcuts are typed right away and repeat
+	kbct.click("leftctrl", vec![("leftctrl", Clicked)]);
+	kbct.click("A", vec![("E", Clicked)]);
+	assert_eq!(None, kbct.kbct.next_deadline());
+	kbct.press("A", vec![("E", Pressed)]);
+	kbct.release("A", vec![("E", Released)]);
+	kbct.release("leftctrl", vec![("leftctrl", Released)]);
+}
+
+#[test]
+fn test_auto_shift_one_shot() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  C: { one_shot: leftctrl }\n\
+		auto_shift: { keys: [A], timeout: 100 }",
+	);
+
+	kbct.click("C", vec![]);
+	kbct.release("C", vec![]);
+	kbct.click("A", vec![("leftctrl", Clicked), ("A", Clicked)]);
+	kbct.press("A", vec![("A", Pressed)]);
+	kbct.release("A", vec![("A", Released), ("leftctrl", Released)]);
+}
+
+#[test]
+fn test_disabled_keys() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  D: E\n\
+		disabled: [D]\n\
+		suppress_while_typing: { keys: [I], timeout: 300 }",
+	);
+
+	kbct.click("D", vec![]);
+	kbct.press("D", vec![]);
+	kbct.release("D", vec![]);
+	assert!(kbct.kbct.source_to_mapped.is_empty());
+
+	kbct.click("I", vec![("I", Clicked)]);
+	kbct.release("I", vec![("I", Released)]);
+
+	kbct.click("A", vec![("A", Clicked)]);
+	kbct.wait(100);
+	kbct.click("I", vec![]);
+	kbct.release("A", vec![("A", Released)]);
+	kbct.wait(300);
+	// A key suppressed while typing stays suppressed until it is released
+	kbct.press("I", vec![]);
+	assert!(kbct.kbct.source_to_mapped.is_empty());
+	kbct.release("I", vec![]);
+
+	kbct.click("I", vec![("I", Clicked)]);
+	kbct.release("I", vec![("I", Released)]);
+
+	// Modifiers do not count as typing
+	kbct.click("leftshift", vec![("leftshift", Clicked)]);
+	kbct.click("I", vec![("I", Clicked)]);
+	kbct.release("I", vec![("I", Released)]);
+	kbct.release("leftshift", vec![("leftshift", Released)]);
+}
+
+#[test]
+fn test_suppress_while_typing_on_another_keyboard() {
+	// The keyboards of a configuration share the time of the latest typed key
+	let yml = "keyboards: []\nsuppress_while_typing: { keys: [I], timeout: 300 }";
+	let mut keyboard = KbctTestContext::from_yaml(yml);
+	let mut touchpad = KbctTestContext::from_yaml(yml);
+	let typing_time = KbctTypingTime::default();
+	keyboard.kbct.share_typing_time(typing_time.clone());
+	touchpad.kbct.share_typing_time(typing_time);
+
+	// Touching the touchpad is not typing
+	touchpad.click("btn_touch", vec![("btn_touch", Clicked)]);
+	touchpad.click("I", vec![("I", Clicked)]);
+	touchpad.release("I", vec![("I", Released)]);
+	touchpad.release("btn_touch", vec![("btn_touch", Released)]);
+
+	keyboard.click("A", vec![("A", Clicked)]);
+	keyboard.release("A", vec![("A", Released)]);
+	touchpad.wait(100);
+	touchpad.click("I", vec![]);
+	touchpad.release("I", vec![]);
+	touchpad.wait(300);
+	touchpad.click("I", vec![("I", Clicked)]);
+	touchpad.release("I", vec![("I", Released)]);
+}
+
+#[test]
+fn test_chord_and_none() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  F: { chord: [leftctrl, B, A] }\n  \
+		  N: none",
+	);
+
+	kbct.click(
+		"F",
+		vec![("leftctrl", Clicked), ("B", Clicked), ("A", Clicked)],
+	);
+	kbct.press("F", vec![("A", Pressed)]);
+	kbct.release(
+		"F",
+		vec![("A", Released), ("B", Released), ("leftctrl", Released)],
+	);
+	assert!(kbct.kbct.mapped_to_source.is_empty());
+
+	// Codes that are held by other keys are neither clicked again nor released
+	kbct.click("B", vec![("B", Clicked)]);
+	kbct.click("F", vec![("leftctrl", Clicked), ("A", Clicked)]);
+	kbct.release("F", vec![("A", Released), ("leftctrl", Released)]);
+	kbct.release("B", vec![("B", Released)]);
+	assert!(kbct.kbct.mapped_to_source.is_empty());
+
+	kbct.click("N", vec![]);
+	kbct.release("N", vec![]);
+	assert!(kbct.kbct.source_to_mapped.is_empty());
+}
+
+#[test]
+fn test_suppressed_modifiers() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  '2': { key: '3', suppress: [leftshift] }\n  \
+		  '4': { key: '5', suppress: [leftshift] }",
+	);
+
+	kbct.click("leftshift", vec![("leftshift", Clicked)]);
+	kbct.click("2", vec![("leftshift", ForceReleased), ("3", Clicked)]);
+	kbct.press("2", vec![("3", Pressed)]);
+	kbct.release("2", vec![("3", Released), ("leftshift", Clicked)]);
+	kbct.click("A", vec![("A", Clicked)]);
+	kbct.release("A", vec![("A", Released)]);
+	kbct.release("leftshift", vec![("leftshift", Released)]);
+
+	// A modifier released while it is suppressed is not pressed back
+	kbct.click("leftshift", vec![("leftshift", Clicked)]);
+	kbct.click("2", vec![("leftshift", ForceReleased), ("3", Clicked)]);
+	kbct.release("leftshift", vec![]);
+	kbct.release("2", vec![("3", Released)]);
+
+	kbct.click("2", vec![("3", Clicked)]);
+	kbct.release("2", vec![("3", Released)]);
+	assert!(kbct.kbct.source_to_mapped.is_empty());
+
+	// A modifier is pressed back for the next key rolled over
+	kbct.click("leftshift", vec![("leftshift", Clicked)]);
+	kbct.click("2", vec![("leftshift", ForceReleased), ("3", Clicked)]);
+	kbct.click("A", vec![("leftshift", Clicked), ("A", Clicked)]);
+	kbct.release("2", vec![("3", Released)]);
+	kbct.release("A", vec![("A", Released)]);
+
+	// Unless the next key suppresses it too, then it is pressed back once that key is released
+	kbct.click("2", vec![("leftshift", ForceReleased), ("3", Clicked)]);
+	kbct.click("4", vec![("5", Clicked)]);
+	kbct.release("2", vec![("3", Released)]);
+	kbct.release("4", vec![("5", Released), ("leftshift", Clicked)]);
+	kbct.release("leftshift", vec![("leftshift", Released)]);
+	assert!(kbct.kbct.source_to_mapped.is_empty());
+}
+
+#[test]
+fn test_tap_alone() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  C: { key: leftctrl, tap_alone: E, timeout: 100 }\n  \
+		  S: { key: leftshift, tap_alone: { modifiers: [leftshift], key: '9' } }",
+	);
+
+	kbct.click("C", vec![("leftctrl", Clicked)]);
+	kbct.release(
+		"C",
+		vec![("leftctrl", Released), ("E", Clicked), ("E", Released)],
+	);
+
+	// Another key is typed with the modifier
+	kbct.click("C", vec![("leftctrl", Clicked)]);
+	kbct.click("A", vec![("A", Clicked)]);
+	kbct.release("A", vec![("A", Released)]);
+	kbct.release("C", vec![("leftctrl", Released)]);
+
+	kbct.click("C", vec![("leftctrl", Clicked)]);
+	kbct.wait(150);
+	kbct.release("C", vec![("leftctrl", Released)]);
+
+	// The modifiers of the tapped key are pressed again after the key itself is released
+	kbct.click("S", vec![("leftshift", Clicked)]);
+	kbct.release(
+		"S",
+		vec![
+			("leftshift", Released),
+			("leftshift", Clicked),
+			("9", Clicked),
+			("9", Released),
+			("leftshift", Released),
+		],
+	);
+}
+
+#[test]
+fn test_layer_conditions() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		layers:\n\
+		- modifiers: [leftctrl]\n  \
+		  exact: true\n  \
+		  keymap:\n    \
+		    I: U\n\
+		- modifiers: [A]\n  \
+		  without: [B]\n  \
+		  keymap:\n    \
+		    I: V",
+	);
+
+	kbct.click("leftctrl", vec![("leftctrl", Clicked)]);
+	kbct.click("I", vec![("leftctrl", ForceReleased), ("U", Clicked)]);
+	kbct.release("I", vec![("U", Released)]);
+
+	// Another modifier is held with the exact layer modifiers
+	kbct.click(
+		"rightalt",
+		vec![("leftctrl", Clicked), ("rightalt", Clicked)],
+	);
+	kbct.click("I", vec![("I", Clicked)]);
+	kbct.release("I", vec![("I", Released)]);
+	kbct.release("rightalt", vec![("rightalt", Released)]);
+	kbct.release("leftctrl", vec![("leftctrl", Released)]);
+
+	kbct.click("A", vec![("A", Clicked)]);
+	kbct.click("I", vec![("A", ForceReleased), ("V", Clicked)]);
+	kbct.release("I", vec![("V", Released)]);
+
+	// The layer is not active while B is held
+	kbct.click("B", vec![("A", Clicked), ("B", Clicked)]);
+	kbct.click("I", vec![("I", Clicked)]);
+	kbct.release("I", vec![("I", Released)]);
+	kbct.release("B", vec![("B", Released)]);
+	kbct.release("A", vec![("A", Released)]);
+
+	let conf = KbctConf::parse(
+		"keyboards: []\nlayers:\n- modifiers: [A]\n  without: [X]\n  keymap:\n    I: V".to_string(),
+	)
+	.unwrap();
+	match Kbct::new(conf, create_keymap_func(|x| if x == "X" { -1 } else { 1 })) {
+		Err(KbctError::Error(k)) => assert_eq!("Configuration contains unknown keys: {\"X\"}", k),
+		_ => panic!("Has to fail"),
+	}
+}
+
+#[test]
+fn test_layer_match() {
+	// Layers match the physical keys by default, and the keys they are mapped to with `match: mapped`
+	// When both layers are active, the precedence rules of the layers apply as usual
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  C: leftctrl\n  \
+		  leftctrl: D\n\
+		layers:\n\
+		- modifiers: [leftctrl]\n  \
+		  keymap:\n    \
+		    J: U\n    \
+		    I: S\n\
+		- modifiers: [leftctrl]\n  \
+		  match: mapped\n  \
+		  keymap:\n    \
+		    K: V\n    \
+		    I: M",
+	);
+
+	kbct.click("C", vec![("leftctrl", Clicked)]);
+	kbct.click("J", vec![("J", Clicked)]);
+	kbct.release("J", vec![("J", Released)]);
+	kbct.click("K", vec![("leftctrl", ForceReleased), ("V", Clicked)]);
+	kbct.release("K", vec![("V", Released)]);
+	kbct.release("C", vec![]);
+
+	kbct.click("leftctrl", vec![("D", Clicked)]);
+	kbct.click("K", vec![("K", Clicked)]);
+	kbct.release("K", vec![("K", Released)]);
+	kbct.click("J", vec![("D", ForceReleased), ("U", Clicked)]);
+	kbct.release("J", vec![("U", Released)]);
+
+	// The layer that is activated last takes precedence
+	kbct.click("C", vec![("D", Clicked), ("leftctrl", Clicked)]);
+	kbct.click(
+		"I",
+		vec![
+			("D", ForceReleased),
+			("leftctrl", ForceReleased),
+			("M", Clicked),
+		],
+	);
+	kbct.release("I", vec![("M", Released)]);
+	kbct.release("C", vec![]);
+	kbct.click("I", vec![("S", Clicked)]);
+	kbct.release("I", vec![("S", Released)]);
+	kbct.release("leftctrl", vec![]);
+}
+
+#[test]
+fn test_mouse_keys() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  W: mouse_wheel_up\n\
+		layers:\n\
+		- modifiers: [rightalt]\n  \
+		  keymap:\n    \
+		    H: mouse_left",
+	);
+
+	kbct.click("rightalt", vec![("rightalt", Clicked)]);
+	kbct.click(
+		"H",
+		vec![("rightalt", ForceReleased), ("mouse_left", Clicked)],
+	);
+	kbct.press("H", vec![("mouse_left", Pressed)]);
+	kbct.release("H", vec![("mouse_left", Released)]);
+	kbct.release("rightalt", vec![]);
+
+	let result = kbct.kbct.map_event(ev(key("W"), Clicked, 0));
+	assert_eq!(KbctEventKind::Mouse, result[0].kind);
+	assert_eq!(
+		Some(MouseMotion::WheelUp),
+		MouseMotion::from_code(result[0].code)
+	);
+	let result = kbct.kbct.map_event(ev(key("A"), Clicked, 0));
+	assert_eq!(KbctEventKind::Key, result[0].kind);
+}
+
+#[test]
+fn test_relative_axes() {
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		axes:\n  \
+		  wheel: { axis: hwheel }\n  \
+		  hwheel: { axis: wheel }\n  \
+		  y: { scale: 1.5, invert: true }\n\
+		layers:\n\
+		- modifiers: [leftmeta]\n  \
+		  keymap:\n    \
+		    mouse_wheel_up: V",
+	);
+	let mut relative = |axis, value| kbct.kbct.map_event(KbctEvent::relative(axis, value, 0));
+
+	use RelativeAxis::*;
+	assert_eq!(vec![KbctEvent::relative(X, 5, 0)], relative(X, 5));
+	assert_eq!(vec![KbctEvent::relative(Y, -3, 0)], relative(Y, 2));
+	// Fractions of the scaled motion are carried over
+	assert_eq!(vec![KbctEvent::relative(Y, -1, 0)], relative(Y, 1));
+	assert_eq!(vec![KbctEvent::relative(Y, -2, 0)], relative(Y, 1));
+	assert_eq!(vec![KbctEvent::relative(HWheel, 1, 0)], relative(Wheel, 1));
+	assert_eq!(
+		vec![KbctEvent::relative(Wheel, -2, 0)],
+		relative(HWheel, -2)
+	);
+
+	// Wheel ticks are typed while their wheel key is mapped
+	kbct.click("leftmeta", vec![("leftmeta", Clicked)]);
+	let result = kbct.kbct.map_event(KbctEvent::relative(Wheel, 2, 0));
+	let expected: Vec<KbctEvent> = vec![
+		("leftmeta", ForceReleased),
+		("V", Clicked),
+		("V", Released),
+		("V", Clicked),
+		("V", Released),
+	]
+	.into_iter()
+	.map(|(x, y)| ev(key(x), y, 0))
+	.collect();
+	assert_eq!(expected, result);
+	assert_eq!(
+		vec![KbctEvent::relative(HWheel, -1, 0)],
+		kbct.kbct.map_event(KbctEvent::relative(Wheel, -1, 0))
+	);
+}
+
+#[test]
+fn test_hi_res_axes() {
+	// The high resolution wheels are mapped along with their wheel
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		axes:\n  \
+		  wheel: { scale: 0.5, invert: true }\n  \
+		  hwheel: { axis: x }\n  \
+		  y: { axis: hwheel }\n\
+		layers:\n\
+		- modifiers: [leftmeta]\n  \
+		  keymap:\n    \
+		    mouse_wheel_up: V",
+	);
+	let mut relative = |axis, value| kbct.kbct.map_event(KbctEvent::relative(axis, value, 0));
+
+	use RelativeAxis::*;
+	assert_eq!(
+		vec![KbctEvent::relative(WheelHiRes, -30, 0)],
+		relative(WheelHiRes, 60)
+	);
+	assert_eq!(vec![KbctEvent::relative(Wheel, -1, 0)], relative(Wheel, 2));
+	// The pointer axes only move by ticks
+	assert_eq!(Vec::<KbctEvent>::new(), relative(HWheelHiRes, 30));
+	assert_eq!(vec![KbctEvent::relative(X, 1, 0)], relative(HWheel, 1));
+	// A pointer axis moved along a wheel moves its high resolution wheel as well
+	assert_eq!(
+		vec![
+			KbctEvent::relative(HWheel, 2, 0),
+			KbctEvent::relative(HWheelHiRes, 240, 0)
+		],
+		relative(Y, 2)
+	);
+
+	// The ticks typed as wheel keys are not scrolled
+	kbct.click("leftmeta", vec![("leftmeta", Clicked)]);
+	let result = kbct.kbct.map_event(KbctEvent::relative(WheelHiRes, 120, 0));
+	assert!(result.is_empty());
+	let result = kbct.kbct.map_event(KbctEvent::relative(Wheel, 1, 0));
+	let expected: Vec<KbctEvent> =
+		vec![("leftmeta", ForceReleased), ("V", Clicked), ("V", Released)]
+			.into_iter()
+			.map(|(x, y)| ev(key(x), y, 0))
+			.collect();
+	assert_eq!(expected, result);
+}
+
+#[test]
+fn test_output_conf() -> Result<()> {
+	let source = KbctDeviceId {
+		bustype: 0x11,
+		vendor: 0x1,
+		product: 0x1,
+		version: 0xab41,
+	};
+
+	let conf = KbctConf::parse("keyboards: []".to_string())?.output();
+	assert_eq!("Kbct-Keyboard", conf.name("Keyboard"));
+	assert!(!conf.copy_id());
+	assert_eq!(source, conf.id(source));
+
+	let yml = "keyboards: []\noutput:\n  name: My keyboard\n  copy_id: true\n  vendor: 0x46d\n  product: 49948";
+	let conf = KbctConf::parse(yml.to_string())?.output();
+	assert_eq!("My keyboard", conf.name("Keyboard"));
+	assert!(conf.copy_id());
+	assert_eq!(
+		KbctDeviceId {
+			vendor: 0x46d,
+			product: 0xc31c,
+			..source
+		},
+		conf.id(source)
+	);
+	Ok(())
+}
+
+#[test]
+fn test_layer_leds() {
+	// The LEDs of all active layers are lit, whether they are held or toggled
+	let mut kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  T: { layer: nav, mode: toggle }\n\
+		layers:\n\
+		- modifiers: [leftctrl]\n  \
+		  led: capslock\n  \
+		  keymap:\n    \
+		    J: U\n\
+		- name: nav\n  \
+		  led: scrolllock\n  \
+		  keymap:\n    \
+		    K: V",
+	);
+	assert!(kbct.kbct.active_leds().is_empty());
+
+	kbct.click("leftctrl", vec![("leftctrl", Clicked)]);
+	assert_eq!(hashset![Led::CapsLock], kbct.kbct.active_leds());
+	kbct.click("T", vec![]);
+	kbct.release("T", vec![]);
+	assert_eq!(
+		hashset![Led::CapsLock, Led::ScrollLock],
+		kbct.kbct.active_leds()
+	);
+	kbct.release("leftctrl", vec![("leftctrl", Released)]);
+	assert_eq!(hashset![Led::ScrollLock], kbct.kbct.active_leds());
+
+	kbct.click("T", vec![]);
+	kbct.release("T", vec![]);
+	assert!(kbct.kbct.active_leds().is_empty());
+}
+
+#[test]
+fn test_output_codes() {
+	let kbct = KbctTestContext::from_yaml(
+		"keyboards: []\n\
+		keymap:\n  \
+		  A: { tap: B, hold: { layer: nav } }\n  \
+		  C: { modifiers: [leftctrl], key: D }\n  \
+		  M: { macro: [{ text: 'x' }] }\n\
+		layers:\n\
+		- name: nav\n  \
+		  keymap:\n    \
+		    H: mouse_left\n    \
+		    J: { chord: [leftalt, E] }\n\
+		combos:\n\
+		- { keys: [F, G], target: esc }\n\
+		auto_shift: { keys: [A] }",
+	);
+	let codes: Vec<_> = vec![
+		"B",
+		"D",
+		"E",
+		"leftctrl",
+		"leftalt",
+		"x",
+		"esc",
+		"leftshift",
+	]
+	.into_iter()
+	.map(key)
+	.chain(std::iter::once(MouseMotion::Left.code()))
+	.collect();
+	let output_codes = kbct.kbct.output_codes();
+	for code in codes {
+		assert!(output_codes.contains(&code), "{} is not written", code);
+	}
+	// Keys that are only read are not written
+	for name in &["A", "C", "F", "H", "J", "M"] {
+		assert!(!output_codes.contains(&key(name)), "{} is written", name);
+	}
+}
@@ -3,6 +3,7 @@ use crate::util::*;
 use crate::util::{KeyEvent, KeyMapEvent};
 use core::time;
 use kbct::KbctError;
+use kbct::KbctOutputConf;
 use kbct::Result;
 use mio::unix::SourceFd;
 use mio::{Interest, Token};
@@ -13,7 +14,7 @@ use std::os::unix::io::AsRawFd;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::time::Duration;
 use std::{io, thread};
-use uinput_sys::EV_KEY;
+use uinput_sys::{EV_KEY, KEY_CNT};
 
 #[derive(PartialEq)]
 enum ReplayMessage {
@@ -24,7 +25,7 @@ enum ReplayMessage {
 
 fn parse_test_case(line: &str, line_number: i32) -> KeyMapEvent {
 	fn parse_key(str: &str) -> KeyEvent {
-		let first = str.chars().nth(0).unwrap();
+		let first = str.chars().next().unwrap();
 		let statuscode = match first {
 			'+' => 1,
 			'=' => 2,
@@ -114,7 +115,9 @@ fn read_keyboard_output(
 pub fn replay(test_file: String, device_name: String) -> Result<()> {
 	use ReplayMessage::*;
 
-	let mut device = create_writable_uinput_device(&device_name)?;
+	let all_keys: Vec<_> = (1..KEY_CNT).collect();
+	let (mut device, _) =
+		create_writable_uinput_device(&device_name, None, &all_keys, &KbctOutputConf::default())?;
 
 	// Allow some time for the kbct process to capture the new device
 	thread::sleep(time::Duration::from_millis(800));
@@ -137,36 +140,37 @@ pub fn replay(test_file: String, device_name: String) -> Result<()> {
 
 	let mut line_number = 1;
 	for line in lines {
-		if let Ok(line) = line {
-			if line.trim().is_empty() || line.trim().starts_with("#") {
-				line_number += 1;
-				continue;
-			}
-			let ev = parse_test_case(&line.as_str(), line_number);
-			device.write(EV_KEY, ev.input.keycode, ev.input.statuscode)?;
-			device.synchronize()?;
-
-			send_wait_for_assert
-				.send(WaitForAssert)
-				.expect("Could not send");
-			match receive_wait_for_key.recv().expect("Coult not receive") {
-				MappedResult(result) => {
-					let expected_str = format!("{}", ev);
-					let actual = KeyMapEvent {
-						input: ev.input,
-						output: result,
-					};
-					let actual_str = format!("{}", actual);
-					assert_eq!(
-						expected_str, actual_str,
-						"Wrong output on line {}",
-						line_number
-					);
-				}
-				_ => panic!("Received illegal value"),
-			}
+		let Ok(line) = line else {
+			continue;
+		};
+		if line.trim().is_empty() || line.trim().starts_with("#") {
 			line_number += 1;
+			continue;
+		}
+		let ev = parse_test_case(line.as_str(), line_number);
+		device.write(EV_KEY, ev.input.keycode, ev.input.statuscode)?;
+		device.synchronize()?;
+
+		send_wait_for_assert
+			.send(WaitForAssert)
+			.expect("Could not send");
+		match receive_wait_for_key.recv().expect("Coult not receive") {
+			MappedResult(result) => {
+				let expected_str = format!("{}", ev);
+				let actual = KeyMapEvent {
+					input: ev.input,
+					output: result,
+				};
+				let actual_str = format!("{}", actual);
+				assert_eq!(
+					expected_str, actual_str,
+					"Wrong output on line {}",
+					line_number
+				);
+			}
+			_ => panic!("Received illegal value"),
 		}
+		line_number += 1;
 	}
 	send_wait_for_assert.send(Finish).unwrap();
 	info!("Test passed");
@@ -16,7 +16,7 @@ curl -s "$URL" |\
  awk '{ printf "%s => \"%s\",\n", $1, $2 }' > /tmp/code_to_name
  */
 
-#[allow(unreachable_patterns)]
+#[allow(unreachable_patterns, clippy::match_overlapping_arm)]
 pub fn code_to_name(x: i32) -> &'static str {
 	match x {
 		0 => "reserved",
@@ -884,6 +884,9 @@ pub fn name_to_code(x: &str) -> i32 {
 		"btn_left" => 0x110,
 		"btn_right" => 0x111,
 		"btn_middle" => 0x112,
+		"mouse_btn_left" => 0x110,
+		"mouse_btn_right" => 0x111,
+		"mouse_btn_middle" => 0x112,
 		"btn_side" => 0x113,
 		"btn_extra" => 0x114,
 		"btn_forward" => 0x115,
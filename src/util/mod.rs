@@ -1,5 +1,6 @@
 pub mod integration_test;
 pub mod keycodes;
+#[allow(clippy::module_inception)]
 mod util;
 
 pub use util::*;
@@ -1,23 +1,45 @@
 use std::fs;
 
 use kbct::Result;
-use kbct::{KbctError, KbctEvent, KbctKeyStatus};
+use kbct::{
+	KbctDeviceId, KbctError, KbctEvent, KbctEventKind, KbctKeyStatus, KbctOutputConf, Led,
+	MouseMotion, RelativeAxis, Timestamp,
+};
 use uinput::Device;
 
 extern crate text_io;
 
 use crate::util::keycodes::{code_to_name, name_to_code};
-use core::{fmt, mem};
+use core::{fmt, mem, slice};
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::libc::{c_char, c_int};
+use nix::sys::stat::Mode;
 use regex::Regex;
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 use std::io::{Error, Read};
-use std::os::unix::io::AsRawFd;
-use uinput_sys::{input_event, EV_KEY};
+use std::os::unix::io::{AsRawFd, RawFd};
+use uinput_sys::*;
 
 // ioctl constants obtained from uinput C library
 const EVIOCGRAB: u32 = 1074021776;
+const EVIOCSCLOCKID: u32 = 1074021792;
 const EVIOCGNAME_256: u32 = 2164278534;
+// Numbers of the ioctls reading the capabilities of a device, the event type or axis is added to them
+const EVIOCGBIT: u32 = 0x20;
+const EVIOCGABS: u32 = 0x40;
+const EVIOCGPROP: u32 = 0x09;
+const EVIOCGID: u32 = 0x02;
+// ioctls setting up a uinput device, which the uinput_sys crate lacks
+const UI_DEV_SETUP: u32 = uinput_write_request(3, mem::size_of::<UinputSetup>());
+const UI_ABS_SETUP: u32 = uinput_write_request(4, mem::size_of::<UinputAbsSetup>());
+
+// The high resolution wheel axes, which the uinput_sys crate lacks
+const REL_WHEEL_HI_RES: i32 = 0x0b;
+const REL_HWHEEL_HI_RES: i32 = 0x0c;
+
+const UINPUT_PATH: &str = "/dev/uinput";
 
 const MAX_EVS: usize = 256;
 pub const BUF_SIZE: usize = mem::size_of::<input_event>() * MAX_EVS;
@@ -28,7 +50,7 @@ pub fn get_uinput_device_name(dev_file_path: &String) -> Result<Option<String>>
 	let file = OpenOptions::new()
 		.read(true)
 		.write(false)
-		.open(&dev_file_path)?;
+		.open(dev_file_path)?;
 	let buff = [0u8; 256];
 	let str_len = unsafe { ioctl_rs::ioctl(file.as_raw_fd(), EVIOCGNAME_256, &buff) };
 	if str_len > 0 {
@@ -41,9 +63,9 @@ pub fn get_uinput_device_name(dev_file_path: &String) -> Result<Option<String>>
 }
 
 fn insert_device_name_path_pair(map: &mut HashMap<String, String>, name: String, path: String) {
-	if !map.contains_key(&name) {
+	if let std::collections::hash_map::Entry::Vacant(e) = map.entry(name.clone()) {
 		// Map doesn't contain a device by this name -- just insert it and return
-		map.insert(name, path);
+		e.insert(path);
 		return;
 	}
 
@@ -51,8 +73,8 @@ fn insert_device_name_path_pair(map: &mut HashMap<String, String>, name: String,
 	let mut i = 1;
 	loop {
 		let fixed_name = format!("{} ({})", name, i);
-		if !map.contains_key(&fixed_name) {
-			map.insert(fixed_name, path);
+		if let std::collections::hash_map::Entry::Vacant(e) = map.entry(fixed_name) {
+			e.insert(path);
 			break;
 		} else {
 			i += 1
@@ -77,10 +99,16 @@ pub fn get_all_uinput_device_names_to_paths() -> Result<HashMap<String, String>>
 }
 
 pub fn open_readable_uinput_device(dev_file_path: &String, should_grab: bool) -> Result<File> {
+	// The state of the LEDs is written back to grabbed devices
 	let file = OpenOptions::new()
 		.read(true)
-		.write(false)
-		.open(&dev_file_path)?;
+		.write(should_grab)
+		.open(dev_file_path)?;
+	// Event timestamps have to be comparable with the monotonic clock used for timers
+	let clock_id = nix::libc::CLOCK_MONOTONIC;
+	if unsafe { ioctl_rs::ioctl(file.as_raw_fd(), EVIOCSCLOCKID, &clock_id) } != 0 {
+		return Err(KbctError::IOError(Error::last_os_error()));
+	}
 	if should_grab {
 		match unsafe { ioctl_rs::ioctl(file.as_raw_fd(), EVIOCGRAB, 1) } {
 			0 => Ok(file),
@@ -91,26 +119,196 @@ pub fn open_readable_uinput_device(dev_file_path: &String, should_grab: bool) ->
 	}
 }
 
-pub fn linux_keyname_mapper(name: &String) -> Option<i32> {
+pub fn linux_keyname_mapper(name: &str) -> Option<i32> {
 	match name_to_code(name) {
 		-1 => None,
 		x => Some(x),
 	}
 }
 
-pub fn create_writable_uinput_device(name: &String) -> Result<Device> {
-	let mut builder = uinput::default()?
-		.name(&name)?
-		.event(uinput::event::Keyboard::All)?
-		.event(uinput::event::Controller::All)?;
-	for item in uinput::event::relative::Position::iter_variants() {
-		builder = builder.event(item)?;
+// Encodes the request of an ioctl reading `size` bytes from an input device, like the _IOR macro
+const fn evdev_read_request(nr: u32, size: usize) -> u32 {
+	(2 << 30) | ((size as u32) << 16) | ((b'E' as u32) << 8) | nr
+}
+
+// Encodes the request of an ioctl writing `size` bytes to the uinput device, like the _IOW macro
+const fn uinput_write_request(nr: u32, size: usize) -> u32 {
+	(1 << 30) | ((size as u32) << 16) | ((b'U' as u32) << 8) | nr
+}
+
+// Returns the codes of an event type the device supports, or its properties
+fn get_device_bits(file: &File, request: u32, count: i32) -> Result<Vec<i32>> {
+	let mut buf = [0u8; KEY_CNT as usize / 8];
+	let request = evdev_read_request(request, (count as usize).div_ceil(8));
+	if unsafe { ioctl_rs::ioctl(file.as_raw_fd(), request, buf.as_mut_ptr()) } < 0 {
+		return Err(KbctError::IOError(Error::last_os_error()));
 	}
-	for item in uinput::event::relative::Wheel::iter_variants() {
-		builder = builder.event(item)?;
+	Ok((0..count)
+		.filter(|x| buf[*x as usize / 8] & (1 << (x % 8)) != 0)
+		.collect())
+}
+
+// The range of an absolute axis, as returned by EVIOCGABS
+#[derive(Default)]
+#[repr(C)]
+struct InputAbsInfo {
+	value: i32,
+	minimum: i32,
+	maximum: i32,
+	fuzz: i32,
+	flat: i32,
+	resolution: i32,
+}
+
+// The argument of UI_DEV_SETUP
+#[repr(C)]
+struct UinputSetup {
+	id: input_id,
+	name: [c_char; UINPUT_MAX_NAME_SIZE as usize],
+	ff_effects_max: u32,
+}
+
+// The argument of UI_ABS_SETUP
+#[repr(C)]
+struct UinputAbsSetup {
+	code: u16,
+	info: InputAbsInfo,
+}
+
+fn get_abs_info(file: &File, code: i32) -> Result<InputAbsInfo> {
+	let mut info = InputAbsInfo::default();
+	let request = evdev_read_request(EVIOCGABS + code as u32, mem::size_of::<InputAbsInfo>());
+	if unsafe { ioctl_rs::ioctl(file.as_raw_fd(), request, &mut info) } < 0 {
+		return Err(KbctError::IOError(Error::last_os_error()));
 	}
-	let x = builder.create()?;
-	Ok(x)
+	Ok(info)
+}
+
+fn get_device_id(file: &File) -> Result<KbctDeviceId> {
+	let mut id: input_id = unsafe { mem::zeroed() };
+	let request = evdev_read_request(EVIOCGID, mem::size_of::<input_id>());
+	if unsafe { ioctl_rs::ioctl(file.as_raw_fd(), request, &mut id) } < 0 {
+		return Err(KbctError::IOError(Error::last_os_error()));
+	}
+	Ok(KbctDeviceId {
+		bustype: id.bustype,
+		vendor: id.vendor,
+		product: id.product,
+		version: id.version,
+	})
+}
+
+// The keys, axes, misc events, switches, LEDs and properties of the source device are mirrored
+// Besides, the given codes can be written, mouse motions add the relative axes of mouse keys
+// The id is copied from the source device if configured, and overridden by the configured fields
+// The returned fd reads the events the system writes to the device, like the state of its LEDs
+pub fn create_writable_uinput_device(
+	name: &str,
+	source: Option<&File>,
+	codes: &[i32],
+	output: &KbctOutputConf,
+) -> Result<(Device, RawFd)> {
+	let fd = nix::fcntl::open(
+		UINPUT_PATH,
+		OFlag::O_RDWR | OFlag::O_NONBLOCK,
+		Mode::empty(),
+	)?;
+	match setup_uinput_device(fd, name, source, codes, output) {
+		Ok(()) => Ok((Device::new(fd), fd)),
+		Err(e) => {
+			let _ = nix::unistd::close(fd);
+			Err(e)
+		}
+	}
+}
+
+fn setup_uinput_device(
+	fd: RawFd,
+	name: &str,
+	source: Option<&File>,
+	codes: &[i32],
+	output: &KbctOutputConf,
+) -> Result<()> {
+	let mut setup: UinputSetup = unsafe { mem::zeroed() };
+	if name.len() >= UINPUT_MAX_NAME_SIZE as usize {
+		return Err(KbctError::Error(format!(
+			"Device name {:?} is too long",
+			name
+		)));
+	}
+	for (i, x) in name.bytes().enumerate() {
+		setup.name[i] = x as c_char;
+	}
+
+	let mut keys: Vec<i32> = codes.iter().copied().filter(|x| *x < KEY_CNT).collect();
+	let mut axes = vec![];
+	if codes.iter().any(|x| MouseMotion::from_code(*x).is_some()) {
+		axes.extend([REL_X, REL_Y, REL_WHEEL, REL_HWHEEL]);
+	}
+	let mut capabilities = vec![];
+	let mut abs_infos = vec![];
+	let mut properties = vec![];
+	let mut id = KbctDeviceId::default();
+	if let Some(source) = source {
+		if output.copy_id() {
+			id = get_device_id(source)?;
+		}
+		keys.extend(get_device_bits(source, EVIOCGBIT + EV_KEY as u32, KEY_CNT)?);
+		axes.extend(get_device_bits(source, EVIOCGBIT + EV_REL as u32, REL_CNT)?);
+		let abs_codes = get_device_bits(source, EVIOCGBIT + EV_ABS as u32, ABS_CNT)?;
+		for code in abs_codes.iter() {
+			abs_infos.push(UinputAbsSetup {
+				code: *code as u16,
+				info: get_abs_info(source, *code)?,
+			});
+		}
+		capabilities.push((EV_ABS, abs_codes));
+		for (ev, count) in [(EV_MSC, MSC_CNT), (EV_SW, SW_CNT), (EV_LED, LED_CNT)] {
+			capabilities.push((ev, get_device_bits(source, EVIOCGBIT + ev as u32, count)?));
+		}
+		properties = get_device_bits(source, EVIOCGPROP, INPUT_PROP_CNT)?;
+	}
+	keys.sort_unstable();
+	keys.dedup();
+	axes.sort_unstable();
+	axes.dedup();
+	capabilities.push((EV_KEY, keys));
+	capabilities.push((EV_REL, axes));
+	let id = output.id(id);
+	setup.id.bustype = id.bustype;
+	setup.id.vendor = id.vendor;
+	setup.id.product = id.product;
+	setup.id.version = id.version;
+
+	for (ev, codes) in capabilities.iter().filter(|(_, codes)| !codes.is_empty()) {
+		let set_bit: unsafe fn(c_int, c_int) -> c_int = match *ev {
+			EV_KEY => ui_set_keybit,
+			EV_REL => ui_set_relbit,
+			EV_ABS => ui_set_absbit,
+			EV_MSC => ui_set_mscbit,
+			EV_LED => ui_set_ledbit,
+			_ => ui_set_swbit,
+		};
+		Errno::result(unsafe { ui_set_evbit(fd, *ev) })?;
+		for code in codes {
+			Errno::result(unsafe { set_bit(fd, *code) })?;
+		}
+	}
+	for property in properties {
+		Errno::result(unsafe { ui_set_propbit(fd, property) })?;
+	}
+
+	// The ranges of the absolute axes, including their resolution
+	for abs_info in abs_infos.iter() {
+		if unsafe { ioctl_rs::ioctl(fd, UI_ABS_SETUP, abs_info) } < 0 {
+			return Err(KbctError::IOError(Error::last_os_error()));
+		}
+	}
+	if unsafe { ioctl_rs::ioctl(fd, UI_DEV_SETUP, &setup) } < 0 {
+		return Err(KbctError::IOError(Error::last_os_error()));
+	}
+	Errno::result(unsafe { ui_dev_create(fd) })?;
+	Ok(())
 }
 
 pub fn map_status_from_linux(val: i32) -> KbctKeyStatus {
@@ -130,22 +328,92 @@ pub fn map_status_from_kbct(val: KbctKeyStatus) -> i32 {
 	}
 }
 
+pub fn map_axis_from_linux(val: i32) -> Option<RelativeAxis> {
+	match val {
+		REL_X => Some(RelativeAxis::X),
+		REL_Y => Some(RelativeAxis::Y),
+		REL_WHEEL => Some(RelativeAxis::Wheel),
+		REL_HWHEEL => Some(RelativeAxis::HWheel),
+		REL_WHEEL_HI_RES => Some(RelativeAxis::WheelHiRes),
+		REL_HWHEEL_HI_RES => Some(RelativeAxis::HWheelHiRes),
+		_ => None,
+	}
+}
+
+pub fn map_axis_from_kbct(val: RelativeAxis) -> i32 {
+	match val {
+		RelativeAxis::X => REL_X,
+		RelativeAxis::Y => REL_Y,
+		RelativeAxis::Wheel => REL_WHEEL,
+		RelativeAxis::HWheel => REL_HWHEEL,
+		RelativeAxis::WheelHiRes => REL_WHEEL_HI_RES,
+		RelativeAxis::HWheelHiRes => REL_HWHEEL_HI_RES,
+	}
+}
+
+pub fn map_led_from_kbct(val: Led) -> i32 {
+	match val {
+		Led::NumLock => LED_NUML,
+		Led::CapsLock => LED_CAPSL,
+		Led::ScrollLock => LED_SCROLLL,
+		Led::Compose => LED_COMPOSE,
+		Led::Kana => LED_KANA,
+	}
+}
+
+pub fn timestamp_from_linux(time: &nix::libc::timeval) -> Timestamp {
+	time.tv_sec as Timestamp * 1000 + time.tv_usec as Timestamp / 1000
+}
+
+pub fn monotonic_now() -> Timestamp {
+	let mut time = nix::libc::timespec {
+		tv_sec: 0,
+		tv_nsec: 0,
+	};
+	unsafe { nix::libc::clock_gettime(nix::libc::CLOCK_MONOTONIC, &mut time) };
+	time.tv_sec as Timestamp * 1000 + time.tv_nsec as Timestamp / 1_000_000
+}
+
 pub fn kbct_from_uinput_event(val: &input_event) -> Option<KbctEvent> {
-	if val.kind as i32 == EV_KEY {
-		Some(KbctEvent {
-			code: val.code as i32,
-			ev_type: map_status_from_linux(val.value),
-		})
-	} else {
-		None
+	let time = timestamp_from_linux(&val.time);
+	match val.kind as i32 {
+		EV_KEY => Some(KbctEvent::new(
+			val.code as i32,
+			map_status_from_linux(val.value),
+			time,
+		)),
+		EV_REL => map_axis_from_linux(val.code as i32)
+			.map(|axis| KbctEvent::relative(axis, val.value, time)),
+		_ => None,
 	}
 }
 
 pub fn read_key_events(file: &mut File, buf: &mut KeyBuffer) -> Result<Vec<input_event>> {
 	let bytes_read = file.read(buf)?;
+	Ok(events_from_buffer(buf, bytes_read))
+}
+
+// Reads the events the system wrote to a uinput device, fails once there are none left
+pub fn read_uinput_device_events(fd: RawFd, buf: &mut KeyBuffer) -> Result<Vec<input_event>> {
+	let bytes_read = nix::unistd::read(fd, buf)?;
+	Ok(events_from_buffer(buf, bytes_read))
+}
+
+fn events_from_buffer(buf: &KeyBuffer, bytes_read: usize) -> Vec<input_event> {
 	let event_count = bytes_read / mem::size_of::<input_event>();
 	let events = unsafe { mem::transmute::<[u8; BUF_SIZE], [input_event; MAX_EVS]>(*buf) };
-	Ok(events[..event_count].to_vec())
+	events[..event_count].to_vec()
+}
+
+// Writes an event to an input device, the kernel sets the time of the event
+pub fn write_input_event(file: &File, kind: i32, code: i32, value: i32) -> Result<()> {
+	let mut ev: input_event = unsafe { mem::zeroed() };
+	ev.kind = kind as u16;
+	ev.code = code as u16;
+	ev.value = value;
+	let ev = unsafe { slice::from_raw_parts(&ev as *const _ as *const u8, mem::size_of_val(&ev)) };
+	nix::unistd::write(file.as_raw_fd(), ev)?;
+	Ok(())
 }
 
 #[derive(PartialEq, Clone, Debug)]
@@ -156,9 +424,13 @@ pub struct KeyEvent {
 
 impl KeyEvent {
 	fn from_kbct_event(ev: &KbctEvent) -> KeyEvent {
+		let statuscode = match ev.kind {
+			KbctEventKind::Relative(value) => value,
+			_ => map_status_from_kbct(ev.ev_type),
+		};
 		KeyEvent {
 			keycode: ev.code,
-			statuscode: map_status_from_kbct(ev.ev_type),
+			statuscode,
 		}
 	}
 }
@@ -170,25 +442,36 @@ pub struct KeyMapEvent {
 }
 
 impl KeyMapEvent {
-	pub fn from_kbct_event(input: KbctEvent, output: &Vec<KbctEvent>) -> KeyMapEvent {
+	pub fn from_kbct_event(input: KbctEvent, output: &[KbctEvent]) -> KeyMapEvent {
 		KeyMapEvent {
 			input: KeyEvent::from_kbct_event(&input),
-			output: output
-				.iter()
-				.map(|x| KeyEvent::from_kbct_event(x))
-				.collect(),
+			output: output.iter().map(KeyEvent::from_kbct_event).collect(),
 		}
 	}
 
+	pub fn format_output(output: &[KbctEvent]) -> String {
+		let target_arr: Vec<String> = output
+			.iter()
+			.map(|x| KeyMapEvent::format_key_event(&KeyEvent::from_kbct_event(x)))
+			.collect();
+		target_arr.join(" ")
+	}
+
 	fn format_key_event(x: &KeyEvent) -> String {
-		let key = code_to_name(x.keycode);
+		if let Some(axis) = RelativeAxis::from_code(x.keycode) {
+			return format!("{}{:+}", axis.name(), x.statuscode);
+		}
+		let key = match MouseMotion::from_code(x.keycode) {
+			Some(motion) => motion.name(),
+			None => code_to_name(x.keycode),
+		};
 		let status = match x.statuscode {
 			1 => "+",
 			0 => "-",
 			2 => "=",
 			_ => panic!("Illegal val"),
 		};
-		format!("{}{}", status.to_string(), key)
+		format!("{}{}", status, key)
 	}
 }
 
@@ -197,7 +480,7 @@ impl fmt::Display for KeyMapEvent {
 		let target_arr: Vec<String> = self
 			.output
 			.iter()
-			.map(|x| KeyMapEvent::format_key_event(x))
+			.map(KeyMapEvent::format_key_event)
 			.collect();
 		let target_str = target_arr.join(" ");
 		write!(
@@ -208,3 +491,57 @@ impl fmt::Display for KeyMapEvent {
 		)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use crate::util::util::*;
+	use std::os::unix::io::FromRawFd;
+
+	#[test]
+	fn test_ioctl_requests() {
+		// The values of the EVIOC* and UI_* macros of linux/input.h and linux/uinput.h
+		assert_eq!(EVIOCGNAME_256, evdev_read_request(0x06, 256));
+		assert_eq!(
+			0x80084502,
+			evdev_read_request(EVIOCGID, mem::size_of::<input_id>())
+		);
+		assert_eq!(
+			0x80184540,
+			evdev_read_request(EVIOCGABS + ABS_X as u32, mem::size_of::<InputAbsInfo>())
+		);
+		assert_eq!(
+			0x80184541,
+			evdev_read_request(EVIOCGABS + ABS_Y as u32, mem::size_of::<InputAbsInfo>())
+		);
+		assert_eq!(
+			0x80604521,
+			evdev_read_request(EVIOCGBIT + EV_KEY as u32, 96)
+		);
+		assert_eq!(0x80024522, evdev_read_request(EVIOCGBIT + EV_REL as u32, 2));
+		assert_eq!(0x80044509, evdev_read_request(EVIOCGPROP, 4));
+		assert_eq!(0x405c5503, UI_DEV_SETUP);
+		assert_eq!(0x401c5504, UI_ABS_SETUP);
+	}
+
+	#[test]
+	fn test_read_write_events() -> Result<()> {
+		// Like the fd of a uinput device, the pipe doesn't block once the events are read
+		let (read_fd, write_fd) = nix::unistd::pipe2(OFlag::O_NONBLOCK)?;
+		let file = unsafe { File::from_raw_fd(write_fd) };
+		write_input_event(&file, EV_LED, LED_CAPSL, 1)?;
+		write_input_event(&file, EV_SYN, SYN_REPORT, 0)?;
+
+		let mut buf: KeyBuffer = [0; BUF_SIZE];
+		let events: Vec<_> = read_uinput_device_events(read_fd, &mut buf)?
+			.iter()
+			.map(|x| (x.kind as i32, x.code as i32, x.value))
+			.collect();
+		assert_eq!(
+			vec![(EV_LED, LED_CAPSL, 1), (EV_SYN, SYN_REPORT, 0)],
+			events
+		);
+		assert!(read_uinput_device_events(read_fd, &mut buf).is_err());
+		nix::unistd::close(read_fd)?;
+		Ok(())
+	}
+}